@@ -0,0 +1,139 @@
+//! Cost of handing a blocking Diesel query a pooled connection, the part of
+//! `db::run` that moving to deadpool-diesel would change. Run with
+//! `cargo bench --bench pool_checkout`.
+//!
+//! Both models use a stub connection, so only the pool and the thread hand-offs are timed.
+//! Postgres itself costs the same either way:
+//!
+//! - `r2d2 + checkout` is what `db::run` does today. It checks out on the blocking pool
+//!   (`db::checkout`), then runs the query in a second `spawn_blocking`.
+//! - `async acquire + interact` is deadpool-diesel's model. It waits for a connection on a
+//!   semaphore without a thread, then runs the query in one `spawn_blocking`. It is modelled
+//!   here, since deadpool itself is not a dependency.
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use diesel::r2d2::{ManageConnection, Pool};
+use tokio::sync::Semaphore;
+
+const POOL_SIZE: u32 = 10;
+const QUERIES: usize = 20_000;
+
+struct StubConnection;
+
+struct StubManager;
+
+impl ManageConnection for StubManager {
+    type Connection = StubConnection;
+    type Error = StubError;
+
+    fn connect(&self) -> Result<StubConnection, StubError> {
+        Ok(StubConnection)
+    }
+
+    fn is_valid(&self, _conn: &mut StubConnection) -> Result<(), StubError> {
+        Ok(())
+    }
+
+    fn has_broken(&self, _conn: &mut StubConnection) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+struct StubError(Infallible);
+
+impl std::fmt::Display for StubError {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {}
+    }
+}
+
+impl std::error::Error for StubError {}
+
+/// The semaphore-guarded pool deadpool builds on
+struct AsyncPool {
+    permits: Semaphore,
+    idle: Mutex<Vec<StubConnection>>,
+}
+
+async fn r2d2_checkout(pool: Pool<StubManager>, work: Duration) {
+    let conn = tokio::task::spawn_blocking(move || pool.get().unwrap())
+        .await
+        .unwrap();
+    tokio::task::spawn_blocking(move || {
+        let _conn = conn;
+        std::thread::sleep(work);
+    })
+    .await
+    .unwrap();
+}
+
+async fn async_interact(pool: Arc<AsyncPool>, work: Duration) {
+    let permit = pool.permits.acquire().await.unwrap();
+    let conn = pool.idle.lock().unwrap().pop().unwrap();
+    let conn = tokio::task::spawn_blocking(move || {
+        std::thread::sleep(work);
+        conn
+    })
+    .await
+    .unwrap();
+    pool.idle.lock().unwrap().push(conn);
+    drop(permit);
+}
+
+/// Run `QUERIES` queries, `concurrency` at a time, returning queries per second
+async fn measure<F, Fut>(concurrency: usize, query: F) -> f64
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    let query = Arc::new(query);
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let query = query.clone();
+            tokio::spawn(async move {
+                for _ in 0..QUERIES / concurrency {
+                    query().await;
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    QUERIES as f64 / start.elapsed().as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    let r2d2 = Pool::builder()
+        .max_size(POOL_SIZE)
+        .build(StubManager)
+        .unwrap();
+    let deadpool = Arc::new(AsyncPool {
+        permits: Semaphore::new(POOL_SIZE as usize),
+        idle: Mutex::new((0..POOL_SIZE).map(|_| StubConnection).collect()),
+    });
+
+    println!("pool of {}, {} queries per run", POOL_SIZE, QUERIES);
+    for (work, concurrency) in [
+        (Duration::ZERO, 1),
+        (Duration::ZERO, 64),
+        (Duration::from_micros(200), 64),
+    ] {
+        let pool = r2d2.clone();
+        let current = measure(concurrency, move || r2d2_checkout(pool.clone(), work)).await;
+        let pool = deadpool.clone();
+        let interact = measure(concurrency, move || async_interact(pool.clone(), work)).await;
+
+        println!(
+            "query {:>6?}, {:>2} concurrent: r2d2 + checkout {:>9.0}/s, async acquire + interact {:>9.0}/s",
+            work, concurrency, current, interact
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! Embeds the git commit and its time, served by `GET /version`.
+
+use std::process::Command;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+fn main() {
+    // Images are often built without `.git`, so the commit can be passed in instead
+    let git_sha = std::env::var("GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| git(&["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // The commit's time rather than the wall clock, which would go stale on rebuilds that
+    // don't rerun this script. `SOURCE_DATE_EPOCH` stands in for it without `.git`.
+    let build_timestamp = source_date_epoch()
+        .or_else(|| {
+            git(&["show", "-s", "--format=%cI", "HEAD"])
+                .and_then(|at| DateTime::parse_from_rfc3339(&at).ok())
+                .map(|at| at.with_timezone(&Utc))
+        })
+        .map(|at| at.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp);
+
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+fn source_date_epoch() -> Option<DateTime<Utc>> {
+    let seconds = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    DateTime::from_timestamp(seconds, 0)
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
@@ -0,0 +1,4 @@
+-- Nothing to undo. On a shared database these tables and indexes are Wanderer's,
+-- created before this migration ran, and dropping them would destroy its data.
+-- Diesel refuses an empty migration, hence the no-op statement.
+SELECT 1;
@@ -0,0 +1,51 @@
+-- Mirrors the Wanderer tables this crate reads. IF NOT EXISTS keeps this a no-op
+-- against a database Wanderer has already set up, so nothing here is guaranteed to
+-- belong to this migration and down.sql leaves it all in place.
+
+CREATE TABLE IF NOT EXISTS map_system_v1 (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    map_id UUID NOT NULL,
+    solar_system_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    custom_name TEXT,
+    description TEXT,
+    tag TEXT,
+    labels TEXT,
+    status BIGINT NOT NULL DEFAULT 0,
+    visible BOOLEAN NOT NULL DEFAULT TRUE,
+    locked BOOLEAN NOT NULL DEFAULT FALSE,
+    position_x BIGINT NOT NULL DEFAULT 0,
+    position_y BIGINT NOT NULL DEFAULT 0,
+    inserted_at TIMESTAMP NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS map_connection_v1 (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    map_id UUID NOT NULL,
+    source_system_id UUID NOT NULL REFERENCES map_system_v1 (id) ON DELETE CASCADE,
+    target_system_id UUID NOT NULL REFERENCES map_system_v1 (id) ON DELETE CASCADE,
+    mass_status BIGINT NOT NULL DEFAULT 0,
+    time_status BIGINT NOT NULL DEFAULT 0,
+    inserted_at TIMESTAMP NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS map_connection_v1_map_id_index ON map_connection_v1 (map_id);
+
+CREATE TABLE IF NOT EXISTS map_system_signatures_v1 (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    system_id UUID NOT NULL REFERENCES map_system_v1 (id) ON DELETE CASCADE,
+    eve_id TEXT NOT NULL,
+    name TEXT,
+    description TEXT,
+    type TEXT,
+    kind TEXT,
+    "group" TEXT,
+    linked_system_id BIGINT,
+    inserted_at TIMESTAMP NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS map_system_signatures_v1_system_id_index
+    ON map_system_signatures_v1 (system_id);
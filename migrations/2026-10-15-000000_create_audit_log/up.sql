@@ -0,0 +1,14 @@
+-- Who changed which row and when. Written by this crate only, in the same transaction
+-- as the change it records.
+
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    table_name TEXT NOT NULL,
+    row_id TEXT NOT NULL,
+    operation TEXT NOT NULL,
+    actor TEXT,
+    recorded_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_table_name_recorded_at_index
+    ON audit_log (table_name, recorded_at);
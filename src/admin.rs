@@ -0,0 +1,101 @@
+use axum::{
+    extract::{Query, State},
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::db::{DbPool, ReadPool};
+use crate::error::ApiError;
+use crate::handlers::AuditRepository;
+use crate::models::{AuditEntry, Page, Pagination};
+use crate::notify::{ListenerStatus, NotifierHandle};
+
+#[derive(Serialize, ToSchema)]
+pub struct PoolStatus {
+    /// Open connections, idle or in use
+    connections: u32,
+    idle_connections: u32,
+    max_size: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AdminStatus {
+    pool: PoolStatus,
+    listener: ListenerStatus,
+}
+
+/// Connection pool and notification listener health, for debugging stalled event streams.
+/// Reads only in-memory counters, so it is cheap to poll.
+#[utoipa::path(
+    get,
+    path = "/admin/status",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Current status", body = AdminStatus),
+        (
+            status = 401,
+            description = "Missing or invalid API key",
+            body = crate::error::ErrorBody
+        ),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool, listener))]
+pub async fn admin_status(
+    State(pool): State<DbPool>,
+    State(listener): State<NotifierHandle>,
+) -> Json<AdminStatus> {
+    let state = pool.state();
+
+    Json(AdminStatus {
+        pool: PoolStatus {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_size: pool.max_size(),
+        },
+        listener: listener.status(),
+    })
+}
+
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub struct AuditParams {
+    /// Only changes to this table, e.g. `map_system_v1`
+    table: Option<String>,
+    /// Only changes recorded at or after this RFC 3339 time
+    since: Option<DateTime<Utc>>,
+}
+
+/// Page through the audit log of changes made through this API, oldest first, to answer
+/// who changed a row and when
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    tag = "admin",
+    params(AuditParams, Pagination),
+    responses(
+        (status = 200, description = "One page of audit entries", body = crate::models::AuditEntryPage),
+        (
+            status = 401,
+            description = "Missing or invalid API key",
+            body = crate::error::ErrorBody
+        ),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(read_pool))]
+pub async fn audit_log(
+    State(read_pool): State<ReadPool>,
+    Query(params): Query<AuditParams>,
+    Query(pagination): Query<Pagination>,
+) -> Result<Json<Page<AuditEntry>>, ApiError> {
+    let since = params.since.map(|since| since.naive_utc());
+    let entries = AuditRepository::get_entries(&read_pool, params.table, since, pagination).await?;
+
+    Ok(Json(entries))
+}
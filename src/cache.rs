@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use ::metrics::counter;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::db::{DbPool, RepoError};
+use crate::handlers::MapSystemRepository;
+use crate::models::{MapSystem, MapVersion, Page, Pagination};
+use crate::notify::SystemNotification;
+
+/// `(limit, offset)` of a cached page
+type PageKey = (i64, i64);
+
+/// A page along with the version of the map it was loaded at
+type VersionedPage = (MapVersion, Page<MapSystem>);
+
+/// Cached pages of each map
+type MapPages = HashMap<Uuid, HashMap<PageKey, VersionedPage>>;
+
+/// Pages of a map's systems as served by `GET /maps/:map_id/systems`, loaded on first read
+/// and kept until a system on the map changes. Each page remembers the map's version as of
+/// its load, so an ETag derived from it always describes the page actually served.
+///
+/// Keep it fresh by feeding it the listener's notifications with [`spawn`](Self::spawn).
+#[derive(Clone, Default)]
+pub struct SystemCache {
+    maps: Arc<Mutex<MapPages>>,
+    /// Bumped on every invalidation, so a page loaded across one is not stored stale
+    generation: Arc<AtomicU64>,
+}
+
+impl SystemCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// One page of the systems on a map and the map's version, from the cache or else from
+    /// the database
+    pub async fn systems_page(
+        &self,
+        pool: &DbPool,
+        map_id: Uuid,
+        pagination: Pagination,
+    ) -> Result<VersionedPage, RepoError> {
+        let key = (pagination.limit(), pagination.offset());
+
+        let cached = self
+            .lock()
+            .get(&map_id)
+            .and_then(|pages| pages.get(&key))
+            .cloned();
+        if let Some(page) = cached {
+            counter!("map_systems_cache_hits_total").increment(1);
+            return Ok(page);
+        }
+        counter!("map_systems_cache_misses_total").increment(1);
+
+        let generation = self.generation.load(Ordering::Acquire);
+        // Read before the page, so a change in between only ever makes the version look stale
+        let version = MapSystemRepository::get_map_version(pool, map_id).await?;
+        let page = MapSystemRepository::get_systems_by_map_id(pool, map_id, pagination).await?;
+
+        let mut maps = self.lock();
+        if self.generation.load(Ordering::Acquire) == generation {
+            maps.entry(map_id)
+                .or_default()
+                .insert(key, (version, page.clone()));
+        }
+
+        Ok((version, page))
+    }
+
+    /// Forget every cached page of a map
+    pub fn invalidate(&self, map_id: Uuid) {
+        let mut maps = self.lock();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        if maps.remove(&map_id).is_some() {
+            debug!("Invalidated cached systems of map {}", map_id);
+        }
+    }
+
+    /// Forget every cached page of every map
+    pub fn clear(&self) {
+        let mut maps = self.lock();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        maps.clear();
+    }
+
+    /// Invalidate maps as their systems change, until the channel closes
+    pub fn spawn(self, mut events: broadcast::Receiver<SystemNotification>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(
+                        notification @ (SystemNotification::Insert(_)
+                        | SystemNotification::Update { .. }
+                        | SystemNotification::Delete { .. }),
+                    ) => {
+                        if let Some(map_id) = notification.map_id() {
+                            self.invalidate(map_id);
+                        }
+                    }
+                    Ok(_) => {}
+                    // The skipped events could have touched any map
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(
+                            "System cache lagged, dropped {} events; clearing it",
+                            skipped
+                        );
+                        self.clear();
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MapPages> {
+        self.maps.lock().expect("system cache lock poisoned")
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+use tracing::{instrument, warn};
+use uuid::Uuid;
+
+use crate::db::{DbPool, RepoError};
+use crate::error::ApiError;
+use crate::handlers::{MapConnectionRepository, MapSystemRepository};
+use crate::models::MapConnection;
+use crate::notify::SystemNotification;
+
+/// EVE solar system ids of the home systems a chain grows from, parsed from a
+/// comma-separated list such as `31000001,31000002`. Repeated ids are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Homes(Vec<i64>);
+
+impl Homes {
+    /// Database ids of the home systems on a map, in the order given
+    pub async fn resolve(&self, pool: &DbPool, map_id: Uuid) -> Result<Vec<Uuid>, ApiError> {
+        let systems = MapSystemRepository::get_by_solar_system_ids(pool, map_id, &self.0).await?;
+
+        self.0
+            .iter()
+            .map(|&solar_system_id| {
+                systems
+                    .iter()
+                    .find(|system| system.solar_system_id == solar_system_id)
+                    .map(|system| system.id)
+                    .ok_or_else(|| {
+                        ApiError::NotFound(format!(
+                            "system {} not found on map {}",
+                            solar_system_id, map_id
+                        ))
+                    })
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Homes {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut homes = Vec::new();
+        for home in value
+            .split(',')
+            .map(str::trim)
+            .filter(|home| !home.is_empty())
+        {
+            let home = home
+                .parse()
+                .map_err(|_| format!("invalid home system {:?}", home))?;
+            if !homes.contains(&home) {
+                homes.push(home);
+            }
+        }
+
+        if homes.is_empty() {
+            return Err("at least one home system is required".to_string());
+        }
+        Ok(Homes(homes))
+    }
+}
+
+impl fmt::Display for Homes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, home) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", home)?;
+        }
+        Ok(())
+    }
+}
+
+/// All system ids reachable from any of `home_system_ids` over the map's connections,
+/// homes included
+#[instrument(skip(pool))]
+pub async fn chain_from_home(
+    pool: &DbPool,
+    map_id: Uuid,
+    home_system_ids: &[Uuid],
+) -> Result<Vec<Uuid>, RepoError> {
+    let connections = MapConnectionRepository::get_connections_by_map_id(pool, map_id).await?;
+    Ok(reachable(&connections, home_system_ids))
+}
+
+/// Breadth-first walk from every home at once, treating connections as undirected edges.
+/// The result is the union of the homes' chains with the homes first.
+///
+/// Wormhole maps routinely contain loops and homes may share a chain, so every system is
+/// visited at most once.
+pub fn reachable(connections: &[MapConnection], home_system_ids: &[Uuid]) -> Vec<Uuid> {
+    let mut neighbors: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for connection in connections {
+        neighbors
+            .entry(connection.source_system_id)
+            .or_default()
+            .push(connection.target_system_id);
+        neighbors
+            .entry(connection.target_system_id)
+            .or_default()
+            .push(connection.source_system_id);
+    }
+
+    let mut visited = HashSet::new();
+    let mut queue: VecDeque<Uuid> = home_system_ids
+        .iter()
+        .copied()
+        .filter(|&home| visited.insert(home))
+        .collect();
+    let mut chain = Vec::new();
+
+    while let Some(system_id) = queue.pop_front() {
+        chain.push(system_id);
+
+        for &next in neighbors.get(&system_id).into_iter().flatten() {
+            if visited.insert(next) {
+                queue.push_back(next);
+            }
+        }
+    }
+
+    chain
+}
+
+/// Tracks which systems are currently in chain from any of a set of home systems, for
+/// filtering notifications. The chain is recomputed whenever a connection on the map
+/// changes.
+pub struct ChainFilter {
+    pool: DbPool,
+    map_id: Uuid,
+    home_system_ids: Vec<Uuid>,
+    systems: HashSet<Uuid>,
+}
+
+impl ChainFilter {
+    pub async fn new(
+        pool: DbPool,
+        map_id: Uuid,
+        home_system_ids: Vec<Uuid>,
+    ) -> Result<Self, RepoError> {
+        let mut filter = Self {
+            pool,
+            map_id,
+            home_system_ids,
+            systems: HashSet::new(),
+        };
+        filter.refresh().await?;
+
+        Ok(filter)
+    }
+
+    /// Recompute the chain from the current connections
+    pub async fn refresh(&mut self) -> Result<(), RepoError> {
+        let chain = chain_from_home(&self.pool, self.map_id, &self.home_system_ids).await?;
+        self.systems = chain.into_iter().collect();
+        Ok(())
+    }
+
+    /// Whether a system is in chain as of the last refresh
+    pub fn contains(&self, system_id: Uuid) -> bool {
+        self.systems.contains(&system_id)
+    }
+
+    /// Whether a notification concerns a system in chain. Connection changes on the map
+    /// refresh the chain first and pass when either end is in the updated chain.
+    pub async fn accepts(&mut self, notification: &SystemNotification) -> bool {
+        match notification {
+            SystemNotification::ConnectionInsert(connection)
+            | SystemNotification::ConnectionUpdate(connection)
+            | SystemNotification::ConnectionDelete(connection) => {
+                if connection.map_id != self.map_id {
+                    return false;
+                }
+
+                if let Err(e) = self.refresh().await {
+                    // Keep filtering against the last known chain rather than dropping events
+                    warn!("Failed to recompute chain for map {}: {:#}", self.map_id, e);
+                }
+
+                self.systems.contains(&connection.source_system_id)
+                    || self.systems.contains(&connection.target_system_id)
+            }
+            _ => notification
+                .system_id()
+                .is_some_and(|system_id| self.systems.contains(&system_id)),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const DEFAULT_PORT: u16 = 3000;
+const DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS: u64 = 10;
+const DEFAULT_POOL_MAX_SIZE: u32 = 10;
+const DEFAULT_POOL_CONNECTION_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_OTLP_GRPC_ENDPOINT: &str = "http://localhost:4317";
+const DEFAULT_OTLP_HTTP_ENDPOINT: &str = "http://localhost:4318";
+const DEFAULT_SERVICE_NAME: &str = "wanderer-connector";
+const DEFAULT_READY_LISTENER_DOWN_SECS: u64 = 30;
+const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 2 * 1024 * 1024;
+const DEFAULT_STREAM_KEEPALIVE_SECS: u64 = 15;
+const DEFAULT_NOTIFY_POLL_INTERVAL_MS: u64 = 2_000;
+
+/// Core settings, read from the environment and validated once at startup.
+///
+/// Optional integrations (webhooks, Discord, rate limits, CORS, TLS) read their own
+/// variables next to the code that uses them.
+#[derive(Clone)]
+pub struct Config {
+    pub database_url: String,
+    /// Replica for read-only queries; reads use `database_url` when unset
+    pub read_database_url: Option<String>,
+    pub pool: PoolConfig,
+    pub run_migrations: bool,
+    /// Address of the HTTP listener, from `HOST` and `PORT`
+    pub bind_addr: SocketAddr,
+    pub shutdown_grace_period: Duration,
+    /// Larger request bodies are rejected with `413 Payload Too Large`
+    pub max_request_body_bytes: usize,
+    /// How long the notification listener may be disconnected before `/ready` fails
+    pub ready_listener_down: Duration,
+    pub api_keys: Vec<String>,
+    /// Collector URL, defaulting to the usual port for `otlp_protocol`
+    pub otlp_endpoint: String,
+    pub otlp_protocol: OtlpProtocol,
+    /// Fail startup when the OTLP exporters cannot be set up instead of logging to the
+    /// console only
+    pub otel_required: bool,
+    pub service_name: String,
+    pub log_format: LogFormat,
+    pub lag_policy: LagPolicy,
+    pub stream_keep_alive: StreamKeepAlive,
+    pub notify_mode: NotifyMode,
+    /// How often the systems table is polled for changes when not listening
+    pub notify_poll_interval: Duration,
+    /// Columns of `map_system_v1` whose changes publish a system update; empty for all
+    pub system_update_columns: Vec<String>,
+    /// Fraction of new traces to sample; `None` samples all of them
+    pub traces_sampler_ratio: Option<f64>,
+}
+
+/// r2d2 pool sizing for the Diesel connection pool
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    pub connection_timeout: Duration,
+    /// Open the `min_idle` connections before startup continues rather than in the
+    /// background
+    pub eager_warmup: bool,
+}
+
+/// How console logs are written, chosen with `LOG_FORMAT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human-readable lines for local development
+    #[default]
+    Pretty,
+    /// One JSON object per event, with span fields, for log aggregators
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("expected pretty or json, got {:?}", other)),
+        }
+    }
+}
+
+/// What SSE and WebSocket streams do with a client that falls more than the broadcast
+/// capacity behind, chosen with `SLOW_CLIENT_POLICY`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LagPolicy {
+    /// Log the gap and carry on from the oldest buffered event
+    #[default]
+    Skip,
+    /// Tell the client to resync and end its stream
+    Disconnect,
+}
+
+impl FromStr for LagPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "skip" => Ok(LagPolicy::Skip),
+            "disconnect" => Ok(LagPolicy::Disconnect),
+            other => Err(format!("expected skip or disconnect, got {:?}", other)),
+        }
+    }
+}
+
+/// How the notification listener learns about changes, chosen with `NOTIFY_MODE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotifyMode {
+    /// LISTEN for the triggers' notifications; startup fails if that is not possible
+    #[default]
+    Listen,
+    /// Poll the systems table by `updated_at`, for servers without LISTEN/NOTIFY.
+    ///
+    /// Only systems are polled, so there are no signature or connection notifications.
+    /// Updates carry no old row. Every poll compares the system ids with those it knows,
+    /// so a deleted system is published as a delete by the next poll.
+    Poll,
+    /// LISTEN, falling back to polling if the first LISTEN fails
+    Auto,
+}
+
+impl FromStr for NotifyMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "listen" => Ok(NotifyMode::Listen),
+            "poll" => Ok(NotifyMode::Poll),
+            "auto" => Ok(NotifyMode::Auto),
+            other => Err(format!("expected listen, poll or auto, got {:?}", other)),
+        }
+    }
+}
+
+/// Transport of the OTLP exporters, chosen with `OTEL_EXPORTER_OTLP_PROTOCOL`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OtlpProtocol {
+    /// gRPC, by default on port 4317
+    #[default]
+    Grpc,
+    /// Protobuf bodies POSTed to `/v1/traces` and `/v1/metrics`, by default on port 4318
+    HttpProtobuf,
+}
+
+impl OtlpProtocol {
+    fn default_endpoint(self) -> &'static str {
+        match self {
+            OtlpProtocol::Grpc => DEFAULT_OTLP_GRPC_ENDPOINT,
+            OtlpProtocol::HttpProtobuf => DEFAULT_OTLP_HTTP_ENDPOINT,
+        }
+    }
+}
+
+impl FromStr for OtlpProtocol {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "grpc" => Ok(OtlpProtocol::Grpc),
+            "http/protobuf" => Ok(OtlpProtocol::HttpProtobuf),
+            other => Err(format!("expected grpc or http/protobuf, got {:?}", other)),
+        }
+    }
+}
+
+/// How long an SSE or WebSocket stream may stay quiet before a keep-alive is sent, so
+/// proxies do not close it as idle. Chosen with `STREAM_KEEPALIVE_SECS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamKeepAlive(pub Duration);
+
+impl Default for StreamKeepAlive {
+    fn default() -> Self {
+        Self(Duration::from_secs(DEFAULT_STREAM_KEEPALIVE_SECS))
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let pool = PoolConfig {
+            max_size: parse_env("DB_POOL_MAX_SIZE")?.unwrap_or(DEFAULT_POOL_MAX_SIZE),
+            min_idle: parse_env("DB_POOL_MIN_IDLE")?,
+            connection_timeout: Duration::from_secs(
+                parse_env("DB_POOL_CONNECTION_TIMEOUT_SECS")?
+                    .unwrap_or(DEFAULT_POOL_CONNECTION_TIMEOUT_SECS),
+            ),
+            eager_warmup: parse_env("DB_EAGER_WARMUP")?.unwrap_or(false),
+        };
+        if pool.max_size == 0 {
+            anyhow::bail!("DB_POOL_MAX_SIZE must be at least 1");
+        }
+        if pool
+            .min_idle
+            .is_some_and(|min_idle| min_idle > pool.max_size)
+        {
+            anyhow::bail!("DB_POOL_MIN_IDLE must not exceed DB_POOL_MAX_SIZE");
+        }
+
+        let traces_sampler_ratio: Option<f64> = parse_env("OTEL_TRACES_SAMPLER_ARG")?;
+        if let Some(ratio) = traces_sampler_ratio {
+            if !(0.0..=1.0).contains(&ratio) {
+                anyhow::bail!(
+                    "OTEL_TRACES_SAMPLER_ARG must be between 0 and 1, got {}",
+                    ratio
+                );
+            }
+        }
+
+        let stream_keep_alive =
+            parse_env("STREAM_KEEPALIVE_SECS")?.unwrap_or(DEFAULT_STREAM_KEEPALIVE_SECS);
+        if stream_keep_alive == 0 {
+            anyhow::bail!("STREAM_KEEPALIVE_SECS must be at least 1");
+        }
+
+        let notify_poll_interval =
+            parse_env("NOTIFY_POLL_INTERVAL_MS")?.unwrap_or(DEFAULT_NOTIFY_POLL_INTERVAL_MS);
+        if notify_poll_interval == 0 {
+            anyhow::bail!("NOTIFY_POLL_INTERVAL_MS must be at least 1");
+        }
+
+        let otlp_protocol: OtlpProtocol =
+            parse_env("OTEL_EXPORTER_OTLP_PROTOCOL")?.unwrap_or_default();
+
+        Ok(Self {
+            database_url: required("DATABASE_URL")?,
+            read_database_url: env::var("READ_DATABASE_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            pool,
+            run_migrations: parse_env("RUN_MIGRATIONS")?.unwrap_or(false),
+            bind_addr: SocketAddr::new(
+                parse_host()?.unwrap_or(DEFAULT_HOST),
+                parse_env("PORT")?.unwrap_or(DEFAULT_PORT),
+            ),
+            shutdown_grace_period: Duration::from_secs(
+                parse_env("SHUTDOWN_GRACE_PERIOD_SECS")?
+                    .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS),
+            ),
+            max_request_body_bytes: parse_env("MAX_REQUEST_BODY_BYTES")?
+                .unwrap_or(DEFAULT_MAX_REQUEST_BODY_BYTES),
+            ready_listener_down: Duration::from_secs(
+                parse_env("READY_LISTENER_DOWN_SECS")?.unwrap_or(DEFAULT_READY_LISTENER_DOWN_SECS),
+            ),
+            api_keys: list_env("API_KEYS"),
+            otlp_endpoint: env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                .unwrap_or_else(|_| otlp_protocol.default_endpoint().to_string()),
+            otlp_protocol,
+            otel_required: parse_env("OTEL_REQUIRED")?.unwrap_or(false),
+            service_name: env::var("OTEL_SERVICE_NAME")
+                .unwrap_or_else(|_| DEFAULT_SERVICE_NAME.to_string()),
+            log_format: parse_env("LOG_FORMAT")?.unwrap_or_default(),
+            lag_policy: parse_env("SLOW_CLIENT_POLICY")?.unwrap_or_default(),
+            stream_keep_alive: StreamKeepAlive(Duration::from_secs(stream_keep_alive)),
+            notify_mode: parse_env("NOTIFY_MODE")?.unwrap_or_default(),
+            notify_poll_interval: Duration::from_millis(notify_poll_interval),
+            system_update_columns: list_env("SYSTEM_UPDATE_COLUMNS"),
+            traces_sampler_ratio,
+        })
+    }
+}
+
+/// Read `HOST` as an IP address. IPv6 literals may be bracketed, and `::` listens on IPv6
+/// and, where the OS allows dual-stack sockets, IPv4 too.
+fn parse_host() -> Result<Option<IpAddr>, anyhow::Error> {
+    let Ok(host) = env::var("HOST") else {
+        return Ok(None);
+    };
+    let literal = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(&host);
+
+    literal.parse().map(Some).map_err(|_| {
+        anyhow::anyhow!(
+            "invalid HOST {:?}: expected an IP address such as 0.0.0.0 or ::",
+            host
+        )
+    })
+}
+
+/// Read a variable that has no sensible default
+fn required(name: &str) -> Result<String, anyhow::Error> {
+    match env::var(name) {
+        Ok(value) if !value.is_empty() => Ok(value),
+        _ => Err(anyhow::anyhow!("{} must be set", name)),
+    }
+}
+
+/// Read a comma-separated list, skipping empty entries
+fn list_env(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Read an optional environment variable, failing loudly if it is set but unparseable
+pub fn parse_env<T>(name: &str) -> Result<Option<T>, anyhow::Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("invalid {} {:?}: {}", name, value, e)),
+        Err(_) => Ok(None),
+    }
+}
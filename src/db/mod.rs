@@ -1,23 +1,514 @@
+use ::metrics::counter;
 use diesel::prelude::*;
-use diesel::r2d2::{self, ConnectionManager};
-use std::env;
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use opentelemetry::KeyValue;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::task::JoinError;
+use tracing::{error, field, info, instrument, warn, Span};
 
+use crate::config::{parse_env, Config};
+use crate::metrics::instruments;
+
+pub mod tls;
+
+use tls::DbTls;
+
+/// Pool of blocking Diesel connections, handed to queries by [`run`].
+///
+/// Kept on r2d2 rather than deadpool-diesel. Deadpool's async acquisition saves about 8µs
+/// of hand-off per query (`benches/pool_checkout.rs`), which is small next to a round trip
+/// to Postgres. r2d2 also keeps `min_idle` connections open and can warm them up before
+/// startup continues, and `DB_POOL_MIN_IDLE` and eager warm-up rely on that.
 pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 pub type DbConnection = r2d2::PooledConnection<ConnectionManager<PgConnection>>;
 
-pub fn establish_connection_pool() -> Result<DbPool, anyhow::Error> {
-    let database_url = env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set");
+/// Pool for read-only queries: a replica from `READ_DATABASE_URL`, or the primary when
+/// none is configured. Derefs to the [`DbPool`] the repository methods take.
+///
+/// A replica lags the primary, so a read right after a write, or right after its
+/// notification, may not see the change yet. Anything that must, such as reacting to a
+/// notification or filling the system cache, should read from the primary.
+#[derive(Clone)]
+pub struct ReadPool {
+    pool: DbPool,
+    replica: bool,
+}
+
+impl ReadPool {
+    /// Read from the primary
+    pub fn primary(pool: DbPool) -> Self {
+        Self {
+            pool,
+            replica: false,
+        }
+    }
+
+    /// Whether reads go to a separate replica
+    pub fn is_replica(&self) -> bool {
+        self.replica
+    }
+}
+
+impl std::ops::Deref for ReadPool {
+    type Target = DbPool;
+
+    fn deref(&self) -> &DbPool {
+        &self.pool
+    }
+}
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+const DEFAULT_STATEMENT_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 50;
+const DEFAULT_SLOW_ACQUIRE_MS: u64 = 100;
+
+/// Extra time given to Postgres to cancel a statement itself before we stop waiting
+const STATEMENT_TIMEOUT_GRACE: Duration = Duration::from_millis(500);
+
+static QUERY_SETTINGS: OnceLock<QuerySettings> = OnceLock::new();
+
+/// How [`run`] executes queries, read once when the pool is built
+#[derive(Debug, Clone, Copy)]
+struct QuerySettings {
+    statement_timeout: Duration,
+    max_retries: u32,
+    retry_backoff: Duration,
+    /// Waiting longer than this for a pooled connection is logged and counted
+    slow_acquire: Duration,
+}
+
+impl Default for QuerySettings {
+    fn default() -> Self {
+        Self {
+            statement_timeout: Duration::from_millis(DEFAULT_STATEMENT_TIMEOUT_MS),
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_backoff: Duration::from_millis(DEFAULT_RETRY_BACKOFF_MS),
+            slow_acquire: Duration::from_millis(DEFAULT_SLOW_ACQUIRE_MS),
+        }
+    }
+}
+
+impl QuerySettings {
+    fn from_env() -> Result<Self, anyhow::Error> {
+        let defaults = Self::default();
+
+        Ok(Self {
+            statement_timeout: parse_env("DB_STATEMENT_TIMEOUT_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.statement_timeout),
+            max_retries: parse_env("DB_MAX_RETRIES")?.unwrap_or(defaults.max_retries),
+            retry_backoff: parse_env("DB_RETRY_BACKOFF_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.retry_backoff),
+            slow_acquire: parse_env("DB_SLOW_ACQUIRE_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.slow_acquire),
+        })
+    }
+}
+
+fn query_settings() -> QuerySettings {
+    QUERY_SETTINGS.get().copied().unwrap_or_default()
+}
+
+/// A query ran longer than `DB_STATEMENT_TIMEOUT_MS`
+#[derive(Debug, thiserror::Error)]
+#[error("database query timed out after {}ms", .0.as_millis())]
+pub struct QueryTimeout(pub Duration);
+
+/// Why a database call made through [`run`] failed
+#[derive(Debug, thiserror::Error)]
+pub enum RepoError {
+    /// No connection could be checked out of the pool
+    #[error("failed to check out a database connection: {0}")]
+    Pool(#[from] r2d2::PoolError),
+    /// The blocking task panicked or was cancelled, so the query's outcome is unknown
+    #[error("blocking database task {task} failed: {source}")]
+    Join {
+        task: &'static str,
+        #[source]
+        source: JoinError,
+    },
+    #[error(transparent)]
+    Timeout(#[from] QueryTimeout),
+    /// Postgres or Diesel rejected the query
+    #[error(transparent)]
+    Db(#[from] DieselError),
+    /// The connection was lost while committing, so the transaction may or may not have
+    /// taken effect. Never retried.
+    #[error("connection lost while committing, the outcome is unknown: {0}")]
+    Commit(#[source] DieselError),
+}
+
+impl RepoError {
+    /// Log a failed blocking task and wrap it, naming the task after the code that
+    /// spawned it
+    fn join(task: &'static str, source: JoinError) -> Self {
+        if source.is_panic() {
+            error!(task, "Blocking database task panicked: {}", source);
+        } else {
+            error!(task, "Blocking database task was cancelled: {}", source);
+        }
+        RepoError::Join { task, source }
+    }
+}
+
+/// Name of the function a closure was written in, e.g.
+/// `wanderer_connector::handlers::MapSystemRepository::get_system_by_id`
+fn task_name<F>() -> &'static str {
+    let mut name = std::any::type_name::<F>();
+    while let Some(outer) = name.strip_suffix("::{{closure}}") {
+        name = outer;
+    }
+    name
+}
+
+/// Sets `statement_timeout` on every connection the pool opens, so Postgres cancels
+/// runaway queries and the connection is freed
+#[derive(Debug)]
+struct StatementTimeout(Duration);
+
+impl CustomizeConnection<PgConnection, r2d2::Error> for StatementTimeout {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        set_statement_timeout(conn, self.0).map_err(r2d2::Error::QueryError)
+    }
+}
+
+fn set_statement_timeout(conn: &mut PgConnection, timeout: Duration) -> QueryResult<()> {
+    diesel::sql_query(format!("SET statement_timeout = {}", timeout.as_millis()))
+        .execute(conn)
+        .map(|_| ())
+}
+
+/// Build the pool. With `DB_EAGER_WARMUP` the idle connections are opened first, so the
+/// server only starts, and `/ready` only passes, once they are available.
+pub fn establish_connection_pool(
+    config: &Config,
+    tls: Option<&DbTls>,
+) -> Result<DbPool, anyhow::Error> {
+    build_pool(&config.database_url, config, tls)
+}
+
+/// Build the pool for reads from `READ_DATABASE_URL` with the same settings as the
+/// primary's, or reuse `primary` when no replica is configured
+pub fn establish_read_pool(
+    config: &Config,
+    tls: Option<&DbTls>,
+    primary: &DbPool,
+) -> Result<ReadPool, anyhow::Error> {
+    match &config.read_database_url {
+        Some(url) => {
+            info!("Routing reads to the replica");
+            Ok(ReadPool {
+                pool: build_pool(url, config, tls)?,
+                replica: true,
+            })
+        }
+        None => Ok(ReadPool::primary(primary.clone())),
+    }
+}
+
+fn build_pool(
+    database_url: &str,
+    config: &Config,
+    tls: Option<&DbTls>,
+) -> Result<DbPool, anyhow::Error> {
+    let database_url = match tls {
+        Some(tls) => tls.libpq_url(database_url),
+        None => database_url.to_string(),
+    };
+    let settings = QuerySettings::from_env()?;
+    let _ = QUERY_SETTINGS.set(settings);
 
     let manager = ConnectionManager::<PgConnection>::new(database_url);
-    let pool = r2d2::Pool::builder()
-        .max_size(10)
-        .build(manager)?;
+    let builder = r2d2::Pool::builder()
+        .max_size(config.pool.max_size)
+        .min_idle(config.pool.min_idle)
+        .connection_timeout(config.pool.connection_timeout)
+        .connection_customizer(Box::new(StatementTimeout(settings.statement_timeout)));
+
+    if !config.pool.eager_warmup {
+        // r2d2 opens the idle connections in the background
+        return Ok(builder.build_unchecked(manager));
+    }
+
+    // Blocks until `min_idle` connections are open, or fails after the connection timeout
+    let start = Instant::now();
+    let pool = builder.build(manager)?;
+    info!(
+        "Warmed up {} database connections in {:?}",
+        pool.state().idle_connections,
+        start.elapsed()
+    );
 
     Ok(pool)
 }
 
+/// Check out a connection, blocking for up to the pool's connection timeout while none is
+/// free. Async code should use [`checkout`] instead.
 pub fn get_connection(pool: &DbPool) -> Result<DbConnection, anyhow::Error> {
-    let conn = pool.get()?;
+    let conn = acquire(pool)?;
+    Ok(conn)
+}
+
+/// Check out a connection on the blocking thread pool, so waiting for a free one under
+/// load never stalls a runtime worker
+pub async fn checkout(pool: &DbPool) -> Result<DbConnection, RepoError> {
+    let pool = pool.clone();
+    let conn = tokio::task::spawn_blocking(move || acquire(&pool))
+        .await
+        .map_err(|e| RepoError::join("checkout", e))??;
     Ok(conn)
 }
+
+/// `pool.get()`, recording how long it waited. A wait past `DB_SLOW_ACQUIRE_MS` usually
+/// means the pool is exhausted, so it is logged with the pool's state and counted.
+fn acquire(pool: &DbPool) -> Result<DbConnection, r2d2::PoolError> {
+    let start = Instant::now();
+    let result = pool.get();
+    let waited = start.elapsed();
+
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    let instruments = instruments();
+    instruments
+        .db_pool_acquire_duration
+        .record(waited.as_secs_f64(), &[KeyValue::new("outcome", outcome)]);
+
+    if waited > query_settings().slow_acquire {
+        let state = pool.state();
+        warn!(
+            waited_ms = waited.as_millis() as u64,
+            connections = state.connections,
+            idle_connections = state.idle_connections,
+            max_size = pool.max_size(),
+            "Slow database connection checkout"
+        );
+        instruments.db_pool_slow_acquires.add(1, &[]);
+        counter!("db_pool_slow_acquires_total").increment(1);
+    }
+
+    result
+}
+
+/// Run a blocking Diesel query on a pooled connection, retrying transient failures with
+/// exponential backoff and giving up with [`QueryTimeout`] once an attempt outlives the
+/// statement timeout. A panic in the query comes back as [`RepoError::Join`] naming the
+/// function the query was written in.
+///
+/// The query may be executed more than once, so it must be safe to repeat. The last
+/// attempt's time in Postgres and its wait for a connection and a blocking thread are
+/// recorded on the `db.query` span as `db.duration_ms` and `db.wait_ms`.
+pub async fn run<F, T>(pool: &DbPool, query: F) -> Result<T, RepoError>
+where
+    F: Fn(&mut PgConnection) -> QueryResult<T> + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    let query = move |conn: &mut PgConnection| query(conn).map_err(RepoError::Db);
+    run_named(pool, task_name::<F>(), Deadline::Statement, query).await
+}
+
+/// Like [`run`], but inside a transaction that is rolled back if `work` fails, for
+/// changes spanning several tables. A retry repeats the whole transaction.
+///
+/// `statement_timeout` bounds each statement rather than the transaction, so there is no
+/// deadline on the transaction as a whole: the caller always learns whether it committed.
+/// A connection lost while committing leaves that unknown and comes back as
+/// [`RepoError::Commit`] without a retry.
+pub async fn with_transaction<F, T>(pool: &DbPool, work: F) -> Result<T, RepoError>
+where
+    F: Fn(&mut PgConnection) -> QueryResult<T> + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    // Named after `work`, as the closure wrapping it below would name this function
+    let task = task_name::<F>();
+    let transaction = move |conn: &mut PgConnection| {
+        let mut committing = false;
+        conn.transaction(|conn| {
+            let result = work(conn);
+            committing = result.is_ok();
+            result
+        })
+        .map_err(|e| {
+            if committing && retryable(&e) {
+                RepoError::Commit(e)
+            } else {
+                RepoError::Db(e)
+            }
+        })
+    };
+    run_named(pool, task, Deadline::Unbounded, transaction).await
+}
+
+/// How long [`run_once`] waits for its blocking task
+#[derive(Debug, Clone, Copy)]
+enum Deadline {
+    /// Give up shortly after the statement timeout, for a single statement
+    Statement,
+    /// Wait for the outcome, for work that Postgres bounds statement by statement
+    Unbounded,
+}
+
+/// [`run`], naming the task `task` in errors
+#[instrument(
+    name = "db.query",
+    skip_all,
+    fields(db.duration_ms = field::Empty, db.wait_ms = field::Empty)
+)]
+async fn run_named<F, T>(
+    pool: &DbPool,
+    task: &'static str,
+    deadline: Deadline,
+    query: F,
+) -> Result<T, RepoError>
+where
+    F: Fn(&mut PgConnection) -> Result<T, RepoError> + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    let settings = query_settings();
+    let mut backoff = settings.retry_backoff;
+    let mut retries = 0;
+    let start = Instant::now();
+
+    let result = loop {
+        match run_once(
+            pool,
+            task,
+            deadline,
+            query.clone(),
+            settings.statement_timeout,
+        )
+        .await
+        {
+            Err(RepoError::Db(e)) if retries < settings.max_retries && retryable(&e) => {
+                retries += 1;
+                warn!(
+                    "Transient database error, retry {}/{} in {:?}: {}",
+                    retries, settings.max_retries, backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            result => break result,
+        }
+    };
+
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    instruments().db_query_duration.record(
+        start.elapsed().as_secs_f64(),
+        &[KeyValue::new("outcome", outcome)],
+    );
+
+    result
+}
+
+/// A single attempt of [`run`].
+///
+/// If we stop waiting first, the blocking task keeps the connection until Postgres
+/// cancels the statement, then hands it back to the pool.
+async fn run_once<F, T>(
+    pool: &DbPool,
+    task: &'static str,
+    deadline: Deadline,
+    query: F,
+    limit: Duration,
+) -> Result<T, RepoError>
+where
+    F: FnOnce(&mut PgConnection) -> Result<T, RepoError> + Send + 'static,
+    T: Send + 'static,
+{
+    let queued = Instant::now();
+    // The pool validates connections on checkout, so a dead one is replaced here. The wait
+    // is bounded by the pool's connection timeout, not the statement timeout.
+    let mut conn = checkout(pool).await?;
+
+    let blocking = tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let result = query(&mut conn);
+        (result, started.duration_since(queued), started.elapsed())
+    });
+    let joined = match deadline {
+        Deadline::Statement => {
+            match tokio::time::timeout(limit + STATEMENT_TIMEOUT_GRACE, blocking).await {
+                Ok(joined) => joined,
+                Err(_) => return Err(QueryTimeout(limit).into()),
+            }
+        }
+        Deadline::Unbounded => blocking.await,
+    };
+    let (result, waited, took) = joined.map_err(|e| RepoError::join(task, e))?;
+    let span = Span::current();
+    span.record("db.wait_ms", waited.as_secs_f64() * 1000.0);
+    span.record("db.duration_ms", took.as_secs_f64() * 1000.0);
+    instruments()
+        .db_statement_duration
+        .record(took.as_secs_f64(), &[]);
+
+    result.map_err(|e| match e {
+        RepoError::Db(e) if is_statement_timeout(&e) => QueryTimeout(limit).into(),
+        e => e,
+    })
+}
+
+/// Whether an error comes from a lost connection rather than the query itself, making it
+/// safe to try again. Logical errors such as constraint violations are never retried.
+pub fn retryable(e: &DieselError) -> bool {
+    match e {
+        DieselError::DatabaseError(DatabaseErrorKind::ClosedConnection, _) => true,
+        DieselError::DatabaseError(DatabaseErrorKind::Unknown, info) => {
+            let message = info.message().to_lowercase();
+            [
+                "broken pipe",
+                "connection reset",
+                "server closed the connection",
+            ]
+            .iter()
+            .any(|transient| message.contains(transient))
+        }
+        _ => false,
+    }
+}
+
+/// Postgres reports a cancelled statement as SQLSTATE 57014, which Diesel does not classify
+fn is_statement_timeout(e: &DieselError) -> bool {
+    matches!(
+        e,
+        DieselError::DatabaseError(DatabaseErrorKind::Unknown, info)
+            if info.message().contains("statement timeout")
+    )
+}
+
+/// Check out a connection and run a trivial query on it
+pub async fn ping(pool: &DbPool) -> Result<(), RepoError> {
+    run(pool, |conn| diesel::sql_query("SELECT 1").execute(conn)).await?;
+
+    Ok(())
+}
+
+/// Apply any embedded migrations the database has not seen yet
+pub async fn run_migrations(pool: &DbPool) -> Result<(), anyhow::Error> {
+    let mut conn = checkout(pool).await?;
+    let timeout = query_settings().statement_timeout;
+
+    let applied = tokio::task::spawn_blocking(move || {
+        // Migrations may legitimately run longer than any API query
+        set_statement_timeout(&mut conn, Duration::ZERO)?;
+        let applied = conn
+            .run_pending_migrations(MIGRATIONS)
+            .map(|versions| versions.len())
+            .map_err(|e| anyhow::anyhow!("failed to run migrations: {}", e));
+        set_statement_timeout(&mut conn, timeout)?;
+
+        applied
+    })
+    .await
+    .map_err(|e| RepoError::join("run_migrations", e))??;
+
+    info!("Applied {} pending migrations", applied);
+
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rustls::{ClientConfig, RootCertStore};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::config::parse_env;
+
+/// Escaped in connection URL parameters: everything but the characters a path is
+/// usually made of and that mean nothing special in a query string
+const URL_PARAM: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// `DATABASE_SSLMODE` values, named after their libpq equivalents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SslMode {
+    Disable,
+    /// Encrypt and verify the certificate chain against the CA bundle
+    Require,
+    /// As `Require`, and also check the certificate matches the host name
+    VerifyFull,
+}
+
+impl SslMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SslMode::Disable => "disable",
+            SslMode::Require => "require",
+            SslMode::VerifyFull => "verify-full",
+        }
+    }
+}
+
+impl FromStr for SslMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "disable" => Ok(SslMode::Disable),
+            "require" => Ok(SslMode::Require),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            other => Err(format!(
+                "expected disable, require or verify-full, got {:?}",
+                other
+            )),
+        }
+    }
+}
+
+/// TLS settings shared by the Diesel pool and the notification listener.
+///
+/// Diesel connects through libpq, which takes its TLS options from the connection URL;
+/// the listener uses rustls with the same CA bundle. rustls always checks the host name,
+/// so the listener behaves as `verify-full` in either mode.
+#[derive(Clone)]
+pub struct DbTls {
+    mode: SslMode,
+    root_cert: PathBuf,
+    connector: MakeRustlsConnect,
+}
+
+impl fmt::Debug for DbTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DbTls")
+            .field("mode", &self.mode)
+            .field("root_cert", &self.root_cert)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DbTls {
+    /// Read `DATABASE_SSLMODE` (default `disable`) and `DATABASE_SSLROOTCERT`, a PEM CA
+    /// bundle that is required whenever TLS is enabled. Returns `None` when disabled.
+    pub fn from_env() -> Result<Option<Self>, anyhow::Error> {
+        let mode = parse_env("DATABASE_SSLMODE")?.unwrap_or(SslMode::Disable);
+        if mode == SslMode::Disable {
+            return Ok(None);
+        }
+
+        let root_cert: PathBuf = parse_env("DATABASE_SSLROOTCERT")?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "DATABASE_SSLROOTCERT must be set when DATABASE_SSLMODE={}",
+                mode.as_str()
+            )
+        })?;
+
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(load_roots(&root_cert)?)
+                .with_no_client_auth();
+
+        Ok(Some(Self {
+            mode,
+            root_cert,
+            connector: MakeRustlsConnect::new(config),
+        }))
+    }
+
+    /// Add the matching `sslmode`/`sslrootcert` parameters to a libpq connection URL. The
+    /// path is percent-encoded, so spaces, `&` or `#` in it survive.
+    pub fn libpq_url(&self, database_url: &str) -> String {
+        let separator = if database_url.contains('?') { '&' } else { '?' };
+        format!(
+            "{}{}sslmode={}&sslrootcert={}",
+            database_url,
+            separator,
+            self.mode.as_str(),
+            utf8_percent_encode(&self.root_cert.to_string_lossy(), URL_PARAM)
+        )
+    }
+
+    /// rustls connector for `tokio-postgres`
+    pub fn connector(&self) -> MakeRustlsConnect {
+        self.connector.clone()
+    }
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, anyhow::Error> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("failed to open CA bundle {}: {}", path.display(), e))?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+        roots.add(cert?)?;
+    }
+
+    if roots.is_empty() {
+        anyhow::bail!("CA bundle {} contains no certificates", path.display());
+    }
+
+    Ok(roots)
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::chain::{ChainFilter, Homes};
+use crate::config::parse_env;
+use crate::db::DbPool;
+use crate::models::MapSystem;
+use crate::notify::SystemNotification;
+use crate::propagation::trace_headers;
+
+const DEFAULT_DEBOUNCE_MS: u64 = 5_000;
+const DEFAULT_TEMPLATE: &str = "{count} new system(s) in chain on map {map}";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Discord rejects messages with more embeds than this
+const MAX_EMBEDS: usize = 10;
+
+/// Posts a Discord message when systems join the chain from any of the home systems.
+///
+/// Systems are usually added to a map before the connection that links them, so an
+/// inserted system is remembered until it becomes reachable from a home. Systems joining
+/// within one debounce window are batched into a single message.
+pub struct DiscordNotifier {
+    client: reqwest::Client,
+    webhook_url: String,
+    map_id: Uuid,
+    homes: Homes,
+    debounce: Duration,
+    template: String,
+}
+
+impl DiscordNotifier {
+    /// Configure from `DISCORD_WEBHOOK_URL`, `DISCORD_MAP_ID`, `DISCORD_HOME_SYSTEM`,
+    /// `DISCORD_DEBOUNCE_MS` and `DISCORD_MESSAGE_TEMPLATE`. Returns `None` when no
+    /// webhook URL is set. `DISCORD_HOME_SYSTEM` may list several comma-separated homes.
+    ///
+    /// The template may use `{count}` and `{map}` placeholders.
+    pub fn from_env() -> Result<Option<Self>, anyhow::Error> {
+        let Some(webhook_url) = std::env::var("DISCORD_WEBHOOK_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+        else {
+            return Ok(None);
+        };
+
+        let map_id = parse_env("DISCORD_MAP_ID")?.ok_or_else(|| {
+            anyhow::anyhow!("DISCORD_MAP_ID must be set with DISCORD_WEBHOOK_URL")
+        })?;
+        let homes = parse_env("DISCORD_HOME_SYSTEM")?.ok_or_else(|| {
+            anyhow::anyhow!("DISCORD_HOME_SYSTEM must be set with DISCORD_WEBHOOK_URL")
+        })?;
+        let debounce = parse_env("DISCORD_DEBOUNCE_MS")?.unwrap_or(DEFAULT_DEBOUNCE_MS);
+        let template = std::env::var("DISCORD_MESSAGE_TEMPLATE")
+            .unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string());
+
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(Some(Self {
+            client,
+            webhook_url,
+            map_id,
+            homes,
+            debounce: Duration::from_millis(debounce),
+            template,
+        }))
+    }
+
+    /// Watch `events` for systems joining the chain until the channel closes, then post
+    /// the alerts still pending
+    pub fn spawn(
+        self,
+        pool: DbPool,
+        events: broadcast::Receiver<SystemNotification>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            if let Err(e) = self.run(pool, events).await {
+                error!("Discord notifier stopped: {:#}", e);
+            }
+        })
+    }
+
+    async fn run(
+        &self,
+        pool: DbPool,
+        mut events: broadcast::Receiver<SystemNotification>,
+    ) -> Result<(), anyhow::Error> {
+        let home_ids = self.homes.resolve(&pool, self.map_id).await?;
+        let mut chain = ChainFilter::new(pool, self.map_id, home_ids).await?;
+
+        info!(
+            "Posting chain alerts for map {} from homes {} to Discord",
+            self.map_id, self.homes
+        );
+
+        // Inserted systems not yet in chain, and systems waiting to be announced
+        let mut candidates: HashMap<Uuid, MapSystem> = HashMap::new();
+        let mut pending: Vec<MapSystem> = Vec::new();
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(notification) => {
+                        // Keeps the chain current on connection changes
+                        chain.accepts(&notification).await;
+
+                        match notification {
+                            SystemNotification::Insert(system) if system.map_id == self.map_id => {
+                                candidates.insert(system.id, system);
+                            }
+                            SystemNotification::Delete { id, .. } => {
+                                candidates.remove(&id);
+                                pending.retain(|system| system.id != id);
+                            }
+                            _ => {}
+                        }
+
+                        let joined: Vec<Uuid> = candidates
+                            .keys()
+                            .copied()
+                            .filter(|id| chain.contains(*id))
+                            .collect();
+                        pending.extend(joined.iter().filter_map(|id| candidates.remove(id)));
+
+                        if !pending.is_empty() && deadline.is_none() {
+                            deadline = Some(Instant::now() + self.debounce);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Discord notifier lagged, skipped {} events", skipped);
+                    }
+                    // Announce what is still waiting out its debounce
+                    Err(RecvError::Closed) => {
+                        self.post(&pending).await;
+                        return Ok(());
+                    }
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    deadline = None;
+                    self.post(&std::mem::take(&mut pending)).await;
+                }
+            }
+        }
+    }
+
+    #[instrument(name = "discord.post", skip_all, fields(systems = systems.len()))]
+    async fn post(&self, systems: &[MapSystem]) {
+        if systems.is_empty() {
+            return;
+        }
+
+        let content = self
+            .template
+            .replace("{count}", &systems.len().to_string())
+            .replace("{map}", &self.map_id.to_string());
+
+        let embeds: Vec<_> = systems
+            .iter()
+            .take(MAX_EMBEDS)
+            .map(|system| {
+                json!({
+                    "title": system.custom_name.as_deref().unwrap_or(&system.name),
+                    "fields": [
+                        { "name": "System", "value": system.name, "inline": true },
+                        {
+                            "name": "Class",
+                            "value": system_class(system.solar_system_id),
+                            "inline": true,
+                        },
+                        { "name": "Map", "value": system.map_id.to_string(), "inline": true },
+                    ],
+                })
+            })
+            .collect();
+
+        let body = json!({ "content": content, "embeds": embeds });
+
+        let request = self
+            .client
+            .post(&self.webhook_url)
+            .headers(trace_headers())
+            .json(&body);
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                info!("Posted {} chain alerts to Discord", systems.len());
+            }
+            Ok(response) => warn!("Discord webhook responded {}", response.status()),
+            Err(e) => warn!("Failed to post to Discord webhook: {}", e),
+        }
+    }
+}
+
+/// Coarse class of a solar system from its EVE id range
+fn system_class(solar_system_id: i64) -> &'static str {
+    match solar_system_id {
+        30_000_000..=30_999_999 => "K-space",
+        31_000_000..=31_999_999 => "W-space",
+        32_000_000..=32_999_999 => "Abyssal",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde::Serialize;
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+use crate::db::{self, RepoError};
+
+/// Error returned by API handlers, rendered as `{ "error": "...", "code": "..." }`
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    MethodNotAllowed(String),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Validation(String),
+    #[error("request body is invalid: {}", describe_fields(.0))]
+    InvalidBody(Vec<FieldError>),
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("rate limit exceeded, retry in {}s", retry_after_secs(.0))]
+    RateLimited(Duration),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    Unavailable(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+/// Whole seconds to put in `Retry-After`, never less than one
+fn retry_after_secs(retry_after: &Duration) -> u64 {
+    (retry_after.as_secs_f64().ceil() as u64).max(1)
+}
+
+/// One reason a request body was rejected
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    /// Path to the offending field, e.g. `name` or `systems[2].id`; absent when the body as
+    /// a whole could not be parsed
+    #[schema(example = "name")]
+    pub field: Option<String>,
+    /// What was wrong with it
+    #[schema(example = "missing field `name`")]
+    pub message: String,
+}
+
+fn describe_fields(fields: &[FieldError]) -> String {
+    fields
+        .iter()
+        .map(|f| match &f.field {
+            Some(field) => format!("{}: {}", field, f.message),
+            None => f.message.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// JSON body of every error response
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody<'a> {
+    /// Human-readable description
+    #[schema(example = "system 5f0c… not found")]
+    error: &'a str,
+    /// Stable machine-readable code, e.g. `not_found` or `rate_limited`
+    #[schema(example = "not_found")]
+    code: &'static str,
+    /// Field-level details, only present for `invalid_body`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<&'a [FieldError]>,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Validation(_) | ApiError::InvalidBody(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable error code for clients to branch on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// The error for a rejection that only comes with a status and a message, such as one
+    /// from an axum extractor or a tower layer, or `None` for a status no variant stands for
+    pub fn from_status(status: StatusCode, message: String) -> Option<Self> {
+        Some(match status {
+            StatusCode::BAD_REQUEST => ApiError::BadRequest(message),
+            StatusCode::UNAUTHORIZED => ApiError::Unauthorized(message),
+            StatusCode::NOT_FOUND => ApiError::NotFound(message),
+            StatusCode::METHOD_NOT_ALLOWED => ApiError::MethodNotAllowed(message),
+            StatusCode::CONFLICT => ApiError::Conflict(message),
+            StatusCode::PAYLOAD_TOO_LARGE => ApiError::PayloadTooLarge(message),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ApiError::UnsupportedMediaType(message),
+            StatusCode::UNPROCESSABLE_ENTITY => ApiError::Validation(message),
+            StatusCode::INTERNAL_SERVER_ERROR => ApiError::Internal(message),
+            StatusCode::SERVICE_UNAVAILABLE => ApiError::Unavailable(message),
+            StatusCode::GATEWAY_TIMEOUT => ApiError::Timeout(message),
+            _ => return None,
+        })
+    }
+
+    /// Map the Diesel errors a client can act on to their own status, or `None` for
+    /// anything that should stay an internal error
+    pub fn from_diesel(e: &DieselError) -> Option<Self> {
+        match e {
+            DieselError::NotFound => Some(ApiError::NotFound("resource not found".to_string())),
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => Some(
+                ApiError::Conflict(info.details().unwrap_or_else(|| info.message()).to_string()),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let message = self.to_string();
+        let body = ErrorBody {
+            error: &message,
+            code: self.code(),
+            fields: match &self {
+                ApiError::InvalidBody(fields) => Some(fields),
+                _ => None,
+            },
+        };
+
+        let mut response = (self.status(), Json(body)).into_response();
+        if let ApiError::RateLimited(retry_after) = &self {
+            response.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(retry_after_secs(retry_after)),
+            );
+        }
+
+        response
+    }
+}
+
+impl From<RepoError> for ApiError {
+    fn from(e: RepoError) -> Self {
+        match &e {
+            RepoError::Db(diesel) => {
+                if let Some(api_error) = ApiError::from_diesel(diesel) {
+                    return api_error;
+                }
+            }
+            RepoError::Timeout(timeout) => {
+                warn!("{}", timeout);
+                return ApiError::Timeout(timeout.to_string());
+            }
+            RepoError::Pool(_) | RepoError::Join { .. } | RepoError::Commit(_) => {}
+        }
+
+        // No connection to be had, as opposed to a query that failed on one
+        let unavailable = match &e {
+            RepoError::Pool(_) => true,
+            RepoError::Db(diesel) => db::retryable(diesel),
+            RepoError::Timeout(_) | RepoError::Join { .. } | RepoError::Commit(_) => false,
+        };
+        if unavailable {
+            error!("Database unavailable: {}", e);
+            return ApiError::Unavailable("database unavailable".to_string());
+        }
+
+        // Keep the details in the logs rather than leaking them to clients
+        error!("Internal error: {}", e);
+        ApiError::Internal("internal server error".to_string())
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::notify::SystemNotification;
+
+/// A notification numbered in the order it was published
+#[derive(Debug, Clone)]
+pub struct LoggedEvent {
+    pub id: u64,
+    pub notification: SystemNotification,
+}
+
+/// What subscribers of an [`EventLog`] receive
+#[derive(Debug, Clone)]
+pub enum LogEntry {
+    Event(Arc<LoggedEvent>),
+    /// Streams of this map should end, e.g. because its systems were deleted
+    MapClosed(Uuid),
+    /// The log fell behind the listener and missed notifications; subscribers should
+    /// resync
+    Gap,
+}
+
+/// Where a subscriber picks up, from [`EventLog::subscribe`]
+pub struct Subscription {
+    /// Events published after the requested id, oldest first
+    pub missed: Vec<Arc<LoggedEvent>>,
+    /// Events after the requested id are no longer kept, so the subscriber should reload
+    pub gap: bool,
+    /// Everything published from now on
+    pub live: broadcast::Receiver<LogEntry>,
+}
+
+/// Numbers every notification and keeps the most recent ones, so a Server-Sent Events
+/// client reconnecting with `Last-Event-Id` can be sent what it missed.
+///
+/// Each map has its own buffer and live channel, so a burst on one map neither pushes
+/// another map's events out nor lags its subscribers. Signature changes carry no map id,
+/// so they are only kept in the log of all events, which chain streams read.
+///
+/// Ids start at the microseconds since the epoch when the log was created, so an id
+/// from before a restart is always older than anything kept and leads to a resync.
+/// Feed it the listener's notifications with [`spawn`](Self::spawn).
+#[derive(Clone)]
+pub struct EventLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    capacity: usize,
+    next_id: u64,
+    all: Ring,
+    maps: HashMap<Uuid, Ring>,
+}
+
+/// The latest events of one stream and the channel that delivers new ones
+struct Ring {
+    /// Lowest id from which every later event of this stream is still kept
+    oldest: u64,
+    events: VecDeque<Arc<LoggedEvent>>,
+    sender: broadcast::Sender<LogEntry>,
+}
+
+impl Ring {
+    fn new(capacity: usize, oldest: u64) -> Self {
+        Self {
+            oldest,
+            events: VecDeque::with_capacity(capacity),
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    fn subscribe(&self, last_event_id: Option<u64>, next_id: u64) -> Subscription {
+        let live = self.sender.subscribe();
+
+        let Some(last_event_id) = last_event_id else {
+            return Subscription {
+                missed: Vec::new(),
+                gap: false,
+                live,
+            };
+        };
+
+        // An id we never handed out, such as one from another instance, is just as
+        // unknown as one that fell out of the buffer. The id comes from the client, so it
+        // may be anything up to u64::MAX.
+        if last_event_id.saturating_add(1) < self.oldest || last_event_id >= next_id {
+            return Subscription {
+                missed: Vec::new(),
+                gap: true,
+                live,
+            };
+        }
+
+        let missed = self
+            .events
+            .iter()
+            .filter(|event| event.id > last_event_id)
+            .cloned()
+            .collect();
+
+        Subscription {
+            missed,
+            gap: false,
+            live,
+        }
+    }
+
+    fn push(&mut self, capacity: usize, event: Arc<LoggedEvent>) {
+        if self.events.len() == capacity {
+            if let Some(evicted) = self.events.pop_front() {
+                self.oldest = evicted.id + 1;
+            }
+        }
+        self.events.push_back(event.clone());
+
+        // No subscribers is fine, the event stays available for resuming
+        let _ = self.sender.send(LogEntry::Event(event));
+    }
+
+    /// Forget the kept events, so no client resumes from before `next_id`
+    fn clear(&mut self, next_id: u64) {
+        self.events.clear();
+        self.oldest = next_id;
+    }
+}
+
+impl EventLog {
+    /// Keep the last `capacity` events of every map and of all maps together, which is
+    /// also the channel capacity of live subscribers
+    pub fn new(capacity: usize) -> Self {
+        let first_id = chrono::Utc::now().timestamp_micros().max(1) as u64;
+
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                next_id: first_id,
+                all: Ring::new(capacity, first_id),
+                maps: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Subscribe to the events of every map after `last_event_id`, or to new events only
+    /// when it is `None`. The missed events and the live receiver are taken together, so
+    /// nothing falls between them or arrives twice.
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> Subscription {
+        let inner = self.lock();
+        inner.all.subscribe(last_event_id, inner.next_id)
+    }
+
+    /// Like [`subscribe`](Self::subscribe), but only to the events of one map
+    pub fn subscribe_map(&self, map_id: Uuid, last_event_id: Option<u64>) -> Subscription {
+        let mut inner = self.lock();
+        let next_id = inner.next_id;
+        inner.map(map_id).subscribe(last_event_id, next_id)
+    }
+
+    /// Number a notification, keep it and pass it on to live subscribers
+    pub fn publish(&self, notification: SystemNotification) {
+        let mut inner = self.lock();
+        let map_id = notification.map_id();
+        let event = Arc::new(LoggedEvent {
+            id: inner.next_id,
+            notification,
+        });
+        inner.next_id += 1;
+
+        let capacity = inner.capacity;
+        inner.all.push(capacity, event.clone());
+        if let Some(map_id) = map_id {
+            inner.map(map_id).push(capacity, event);
+        }
+    }
+
+    /// End the live streams of a map. Its kept events go too, so a client resuming
+    /// into the closed map resyncs.
+    pub fn close_map(&self, map_id: Uuid) {
+        let mut inner = self.lock();
+        let next_id = inner.next_id;
+        let _ = inner.all.sender.send(LogEntry::MapClosed(map_id));
+        let ring = inner.map(map_id);
+        ring.clear(next_id);
+        let _ = ring.sender.send(LogEntry::MapClosed(map_id));
+    }
+
+    /// Forget every kept event after notifications were lost, so no client resumes
+    /// across the hole
+    fn mark_gap(&self) {
+        let mut guard = self.lock();
+        let inner = &mut *guard;
+        let next_id = inner.next_id;
+        for ring in std::iter::once(&mut inner.all).chain(inner.maps.values_mut()) {
+            ring.clear(next_id);
+            let _ = ring.sender.send(LogEntry::Gap);
+        }
+    }
+
+    /// Log notifications until the channel closes
+    pub fn spawn(self, mut events: broadcast::Receiver<SystemNotification>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(notification) => self.publish(notification),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(
+                            "Event log lagged, dropped {} events; clients must resync",
+                            skipped
+                        );
+                        self.mark_gap();
+                    }
+                    Err(RecvError::Closed) => {
+                        debug!("Event log stopped, the listener is gone");
+                        break;
+                    }
+                }
+            }
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("event log lock poisoned")
+    }
+}
+
+impl Inner {
+    /// The ring of a map, created on first use
+    fn map(&mut self, map_id: Uuid) -> &mut Ring {
+        let (capacity, oldest) = (self.capacity, self.all.oldest);
+        self.maps
+            .entry(map_id)
+            .or_insert_with(|| Ring::new(capacity, oldest))
+    }
+}
@@ -0,0 +1,129 @@
+//! Request extractors whose rejections render as [`ApiError`] bodies rather than axum's
+//! plain-text defaults, and a response mapper for the rejections raised elsewhere.
+
+use axum::{
+    async_trait,
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::header::{CONTENT_LENGTH, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+    BoxError, Json,
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::{ApiError, FieldError};
+
+/// Drop-in for [`axum::Json`] that rejects a body which is not valid JSON, or does not
+/// match `T`, with a `422` naming the field at fault
+///
+/// Other rejections keep axum's status, e.g. `415` for a missing `Content-Type` or `413`
+/// for a body over the limit, with the usual error body.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApiJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // Parse to a value first so syntax errors are told apart from ones in the shape
+        let Json(value) = Json::<serde_json::Value>::from_request(req, state)
+            .await
+            .map_err(|rejection| match rejection {
+                JsonRejection::JsonSyntaxError(e) => ApiError::InvalidBody(vec![FieldError {
+                    field: None,
+                    message: syntax_message(&e),
+                }])
+                .into_response(),
+                other => match ApiError::from_status(other.status(), other.body_text()) {
+                    Some(error) => error.into_response(),
+                    None => other.into_response(),
+                },
+            })?;
+
+        serde_path_to_error::deserialize(value)
+            .map(ApiJson)
+            .map_err(|e| ApiError::InvalidBody(vec![field_error(e)]).into_response())
+    }
+}
+
+/// The parser's own message, without axum's "Failed to parse…" prefix
+fn syntax_message(e: &impl std::error::Error) -> String {
+    let mut source: &dyn std::error::Error = e;
+    while let Some(inner) = source.source() {
+        source = inner;
+    }
+    source.to_string()
+}
+
+/// Point at the field the error is about. serde reports a missing field against its
+/// parent, so the field's own name is appended to the path.
+fn field_error(e: serde_path_to_error::Error<serde_json::Error>) -> FieldError {
+    let parent = match e.path().to_string() {
+        root if root == "." => None,
+        path => Some(path),
+    };
+    let message = e.inner().to_string();
+    let missing = message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.strip_suffix('`'))
+        .map(str::to_string);
+
+    let field = match (parent, missing) {
+        (Some(parent), Some(missing)) => Some(format!("{}.{}", parent, missing)),
+        (None, Some(missing)) => Some(missing),
+        (parent, None) => parent,
+    };
+
+    FieldError { field, message }
+}
+
+/// Longest plain-text error body [`render_plain_errors`] reads
+const MAX_PLAIN_ERROR_BYTES: usize = 4096;
+
+/// Re-render error responses that did not come from an [`ApiError`] as the usual error body:
+/// the `413` of the body limit layer, which answers before any extractor runs, and the
+/// rejections of extractors such as `Path` and `Query`. Their text becomes the message.
+pub async fn render_plain_errors<B>(response: Response<B>) -> Response
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let response = response.map(Body::new);
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if is_json || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let mut message = match to_bytes(body, MAX_PLAIN_ERROR_BYTES).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).trim().to_string(),
+        Err(_) => String::new(),
+    };
+    if message.is_empty() {
+        message = status.canonical_reason().unwrap_or("error").to_lowercase();
+    }
+    parts.headers.remove(CONTENT_LENGTH);
+
+    match ApiError::from_status(status, message.clone()) {
+        Some(error) => {
+            let mut rendered = error.into_response();
+            // Keep what else the response said, e.g. `Allow`
+            parts.headers.remove(CONTENT_TYPE);
+            for (name, value) in &parts.headers {
+                rendered.headers_mut().insert(name, value.clone());
+            }
+            rendered
+        }
+        None => Response::from_parts(parts, Body::from(message)),
+    }
+}
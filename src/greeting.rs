@@ -0,0 +1,125 @@
+//! Demo greeting endpoints, left over from the template this crate started as. Built
+//! with the default `demo-endpoints` feature; production builds can leave them out with
+//! `--no-default-features`.
+
+use axum::{
+    extract::Query,
+    response::Json,
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::error::ApiError;
+use crate::extract::ApiJson;
+use crate::state::AppState;
+
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct QueryParams {
+    name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub(crate) struct GreetingRequest {
+    name: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct GreetingResponse {
+    message: String,
+}
+
+/// Names longer than this many characters are rejected by the greeting endpoints
+const MAX_NAME_CHARS: usize = 100;
+
+/// Trim a name to greet and reject it if that leaves nothing or too much
+fn greeting_name(raw: &str) -> Result<&str, ApiError> {
+    let name = raw.trim();
+    if name.is_empty() {
+        return Err(ApiError::BadRequest("name must not be empty".to_string()));
+    }
+    if name.chars().count() > MAX_NAME_CHARS {
+        return Err(ApiError::BadRequest(format!(
+            "name must be at most {} characters",
+            MAX_NAME_CHARS
+        )));
+    }
+
+    Ok(name)
+}
+
+/// Simple greeting endpoint with query parameters
+#[utoipa::path(
+    get,
+    path = "/hello",
+    tag = "greeting",
+    params(QueryParams),
+    responses(
+        (status = 200, description = "Greeting", body = GreetingResponse),
+        (status = 400, description = "Empty or overlong name", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+// The name is logged once validated, so an overlong one never reaches the logs
+#[instrument(skip_all)]
+pub(crate) async fn hello(
+    Query(params): Query<QueryParams>,
+) -> Result<Json<GreetingResponse>, ApiError> {
+    let name = match params.name.as_deref() {
+        Some(raw) => greeting_name(raw)?,
+        None => "World",
+    };
+    info!("Greeting requested for: {}", name);
+
+    Ok(Json(GreetingResponse {
+        message: format!("Hello, {}!", name),
+    }))
+}
+
+/// Greeting endpoint with JSON body
+#[utoipa::path(
+    post,
+    path = "/greet",
+    tag = "greeting",
+    request_body = GreetingRequest,
+    responses(
+        (status = 200, description = "Greeting", body = GreetingResponse),
+        (status = 400, description = "Empty or overlong name", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 422, description = "Body is not JSON or is missing fields", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip_all)]
+pub(crate) async fn greet_json(
+    ApiJson(payload): ApiJson<GreetingRequest>,
+) -> Result<Json<GreetingResponse>, ApiError> {
+    let name = greeting_name(&payload.name)?;
+    info!("JSON greeting requested for: {}", name);
+
+    Ok(Json(GreetingResponse {
+        message: format!("Hello, {}! (from JSON)", name),
+    }))
+}
+
+/// Greeting routes, to be merged behind authentication
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/hello", get(hello))
+        .route("/greet", post(greet_json))
+}
+
+/// The greeting endpoints' part of the OpenAPI document
+#[derive(OpenApi)]
+#[openapi(
+    paths(hello, greet_json),
+    components(schemas(GreetingRequest, GreetingResponse)),
+    tags((name = "greeting", description = "Demo greetings"))
+)]
+pub(crate) struct GreetingApiDoc;
@@ -0,0 +1,670 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::NaiveDateTime;
+use diesel::dsl::{count_star, max, now};
+use diesel::prelude::*;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::db::{self, DbPool, RepoError};
+use crate::models::{
+    parse_labels, AuditEntry, MapConnection, MapSignature, MapSystem, MapVersion, Neighbor,
+    NewAuditEntry, Operation, Page, Pagination, SyncSummary, SystemSnapshot,
+};
+use crate::schema::{audit_log, map_connection_v1, map_system_signatures_v1, map_system_v1};
+
+pub struct MapSystemRepository;
+
+impl MapSystemRepository {
+    /// Load every system across all maps
+    #[instrument(skip(pool))]
+    pub async fn get_all_systems(pool: &DbPool) -> Result<Vec<MapSystem>, RepoError> {
+        let systems = db::run(pool, move |conn| {
+            map_system_v1::table
+                .select(MapSystem::as_select())
+                .load(conn)
+        })
+        .await?;
+
+        Ok(systems)
+    }
+
+    /// Load a single system by its database id
+    #[instrument(skip(pool))]
+    pub async fn get_system_by_id(
+        pool: &DbPool,
+        system_id: Uuid,
+    ) -> Result<Option<MapSystem>, RepoError> {
+        let system = db::run(pool, move |conn| {
+            map_system_v1::table
+                .find(system_id)
+                .select(MapSystem::as_select())
+                .first(conn)
+                .optional()
+        })
+        .await?;
+
+        Ok(system)
+    }
+
+    /// Look a system up by its EVE solar system id within a map
+    #[instrument(skip(pool))]
+    pub async fn get_by_solar_system_id(
+        pool: &DbPool,
+        map_id: Uuid,
+        solar_system_id: i64,
+    ) -> Result<Option<MapSystem>, RepoError> {
+        let system = db::run(pool, move |conn| {
+            map_system_v1::table
+                .filter(map_system_v1::map_id.eq(map_id))
+                .filter(map_system_v1::solar_system_id.eq(solar_system_id))
+                .select(MapSystem::as_select())
+                .first(conn)
+                .optional()
+        })
+        .await?;
+
+        Ok(system)
+    }
+
+    /// Look several systems up by their EVE solar system ids within a map. Ids not on the
+    /// map are left out.
+    #[instrument(skip(pool))]
+    pub async fn get_by_solar_system_ids(
+        pool: &DbPool,
+        map_id: Uuid,
+        solar_system_ids: &[i64],
+    ) -> Result<Vec<MapSystem>, RepoError> {
+        let solar_system_ids = solar_system_ids.to_vec();
+        let systems = db::run(pool, move |conn| {
+            map_system_v1::table
+                .filter(map_system_v1::map_id.eq(map_id))
+                .filter(map_system_v1::solar_system_id.eq_any(&solar_system_ids))
+                .select(MapSystem::as_select())
+                .load(conn)
+        })
+        .await?;
+
+        Ok(systems)
+    }
+
+    /// Load one page of the systems belonging to a single map
+    #[instrument(skip(pool))]
+    pub async fn get_systems_by_map_id(
+        pool: &DbPool,
+        map_id: Uuid,
+        pagination: Pagination,
+    ) -> Result<Page<MapSystem>, RepoError> {
+        let limit = pagination.limit();
+        let offset = pagination.offset();
+
+        let (items, total) = db::run(pool, move |conn| {
+            let total = map_system_v1::table
+                .filter(map_system_v1::map_id.eq(map_id))
+                .count()
+                .get_result::<i64>(conn)?;
+
+            let items = map_system_v1::table
+                .filter(map_system_v1::map_id.eq(map_id))
+                .order(map_system_v1::name.asc())
+                .limit(limit)
+                .offset(offset)
+                .select(MapSystem::as_select())
+                .load::<MapSystem>(conn)?;
+
+            Ok::<_, diesel::result::Error>((items, total))
+        })
+        .await?;
+
+        Ok(Page {
+            items,
+            total,
+            limit,
+            offset,
+        })
+    }
+
+    /// The [`MapVersion`] of a map's systems
+    #[instrument(skip(pool))]
+    pub async fn get_map_version(pool: &DbPool, map_id: Uuid) -> Result<MapVersion, RepoError> {
+        let version = db::run(pool, move |conn| {
+            map_system_v1::table
+                .filter(map_system_v1::map_id.eq(map_id))
+                .select((count_star(), max(map_system_v1::updated_at)))
+                .first(conn)
+        })
+        .await?;
+
+        Ok(version)
+    }
+
+    /// Load one page of the systems on a map carrying `label`, compared case-insensitively.
+    ///
+    /// The column may hold JSON or a comma-separated list, so Postgres only narrows the
+    /// candidates and the parsed labels decide.
+    #[instrument(skip(pool))]
+    pub async fn get_systems_by_label(
+        pool: &DbPool,
+        map_id: Uuid,
+        label: String,
+        pagination: Pagination,
+    ) -> Result<Page<MapSystem>, RepoError> {
+        let limit = pagination.limit();
+        let offset = pagination.offset();
+
+        let pattern = label_pattern(&label);
+        let candidates = db::run(pool, move |conn| {
+            map_system_v1::table
+                .filter(map_system_v1::map_id.eq(map_id))
+                .filter(map_system_v1::labels.ilike(&pattern))
+                .order(map_system_v1::name.asc())
+                .select(MapSystem::as_select())
+                .load::<MapSystem>(conn)
+        })
+        .await?;
+
+        let matching: Vec<MapSystem> = candidates
+            .into_iter()
+            .filter(|system| has_label(&system.labels, &label))
+            .collect();
+
+        Ok(Page {
+            total: matching.len() as i64,
+            items: matching
+                .into_iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .collect(),
+            limit,
+            offset,
+        })
+    }
+
+    /// Count the systems on a map, optionally only those carrying `label`. Without a label
+    /// this is a single `COUNT(*)`; with one only the candidates' labels are loaded.
+    #[instrument(skip(pool))]
+    pub async fn count_systems(
+        pool: &DbPool,
+        map_id: Uuid,
+        label: Option<String>,
+    ) -> Result<i64, RepoError> {
+        let Some(label) = label else {
+            let count = db::run(pool, move |conn| {
+                map_system_v1::table
+                    .filter(map_system_v1::map_id.eq(map_id))
+                    .count()
+                    .get_result::<i64>(conn)
+            })
+            .await?;
+
+            return Ok(count);
+        };
+
+        let pattern = label_pattern(&label);
+        let candidates = db::run(pool, move |conn| {
+            map_system_v1::table
+                .filter(map_system_v1::map_id.eq(map_id))
+                .filter(map_system_v1::labels.ilike(&pattern))
+                .select(map_system_v1::labels)
+                .load::<Option<String>>(conn)
+        })
+        .await?;
+
+        let count = candidates
+            .iter()
+            .flatten()
+            .filter(|raw| has_label(&parse_labels(raw), &label))
+            .count();
+
+        Ok(count as i64)
+    }
+
+    /// Delete every system on a map, with their signatures and connections, in one
+    /// transaction and return how many systems were removed.
+    #[instrument(skip(pool))]
+    pub async fn delete_by_map_id(
+        pool: &DbPool,
+        map_id: Uuid,
+        actor: Option<String>,
+    ) -> Result<usize, RepoError> {
+        let deleted = db::with_transaction(pool, move |conn| {
+            let systems = map_system_v1::table
+                .filter(map_system_v1::map_id.eq(map_id))
+                .select(map_system_v1::id);
+            let signatures = diesel::delete(
+                map_system_signatures_v1::table
+                    .filter(map_system_signatures_v1::system_id.eq_any(systems)),
+            )
+            .returning(map_system_signatures_v1::id)
+            .get_results::<Uuid>(conn)?;
+            let connections = diesel::delete(
+                map_connection_v1::table.filter(map_connection_v1::map_id.eq(map_id)),
+            )
+            .returning(map_connection_v1::id)
+            .get_results::<Uuid>(conn)?;
+            let systems =
+                diesel::delete(map_system_v1::table.filter(map_system_v1::map_id.eq(map_id)))
+                    .returning(map_system_v1::id)
+                    .get_results::<Uuid>(conn)?;
+
+            let actor = actor.as_deref();
+            AuditRepository::record(
+                conn,
+                "map_system_signatures_v1",
+                &signatures,
+                Operation::Delete,
+                actor,
+            )?;
+            AuditRepository::record(
+                conn,
+                "map_connection_v1",
+                &connections,
+                Operation::Delete,
+                actor,
+            )?;
+            AuditRepository::record(conn, "map_system_v1", &systems, Operation::Delete, actor)?;
+
+            Ok(systems.len())
+        })
+        .await?;
+
+        Ok(deleted)
+    }
+
+    /// Delete a system along with its signatures and connections, all or nothing. Returns
+    /// whether the system existed.
+    ///
+    /// The dependents are removed explicitly rather than left to foreign keys, which the
+    /// tables Wanderer owns may not cascade, and so that each of them is audited.
+    #[instrument(skip(pool))]
+    pub async fn delete_system(
+        pool: &DbPool,
+        system_id: Uuid,
+        actor: Option<String>,
+    ) -> Result<bool, RepoError> {
+        let deleted = db::with_transaction(pool, move |conn| {
+            let signatures = diesel::delete(
+                map_system_signatures_v1::table
+                    .filter(map_system_signatures_v1::system_id.eq(system_id)),
+            )
+            .returning(map_system_signatures_v1::id)
+            .get_results::<Uuid>(conn)?;
+            let connections = diesel::delete(
+                map_connection_v1::table.filter(
+                    map_connection_v1::source_system_id
+                        .eq(system_id)
+                        .or(map_connection_v1::target_system_id.eq(system_id)),
+                ),
+            )
+            .returning(map_connection_v1::id)
+            .get_results::<Uuid>(conn)?;
+            let systems = diesel::delete(map_system_v1::table.find(system_id))
+                .returning(map_system_v1::id)
+                .get_results::<Uuid>(conn)?;
+
+            let actor = actor.as_deref();
+            AuditRepository::record(
+                conn,
+                "map_system_signatures_v1",
+                &signatures,
+                Operation::Delete,
+                actor,
+            )?;
+            AuditRepository::record(
+                conn,
+                "map_connection_v1",
+                &connections,
+                Operation::Delete,
+                actor,
+            )?;
+            AuditRepository::record(conn, "map_system_v1", &systems, Operation::Delete, actor)?;
+
+            Ok(!systems.is_empty())
+        })
+        .await?;
+
+        Ok(deleted)
+    }
+
+    /// Move a system on its map, touching nothing but its position and `updated_at`, and
+    /// audit the move. Returns the moved system, or `None` when there is no such system.
+    #[instrument(skip(pool))]
+    pub async fn set_position(
+        pool: &DbPool,
+        system_id: Uuid,
+        x: i64,
+        y: i64,
+        actor: Option<String>,
+    ) -> Result<Option<MapSystem>, RepoError> {
+        let system = db::with_transaction(pool, move |conn| {
+            let system = diesel::update(map_system_v1::table.find(system_id))
+                .set((
+                    map_system_v1::position_x.eq(x),
+                    map_system_v1::position_y.eq(y),
+                    map_system_v1::updated_at.eq(now),
+                ))
+                .returning(MapSystem::as_returning())
+                .get_result(conn)
+                .optional()?;
+
+            if let Some(system) = &system {
+                AuditRepository::record(
+                    conn,
+                    "map_system_v1",
+                    &[system.id],
+                    Operation::Update,
+                    actor.as_deref(),
+                )?;
+            }
+
+            Ok(system)
+        })
+        .await?;
+
+        Ok(system)
+    }
+
+    /// Reconcile a map to `snapshot` in one transaction: insert the systems that are new,
+    /// update those that differ, and delete those missing from it along with their
+    /// signatures and connections. Each change fires the usual notify trigger and is
+    /// audited.
+    ///
+    /// Systems are matched on their solar system id, which must be unique in `snapshot`.
+    /// Syncs of the same map run one after the other, even while the map has no systems.
+    #[instrument(skip(pool, snapshot), fields(systems = snapshot.len()))]
+    pub async fn sync_map(
+        pool: &DbPool,
+        map_id: Uuid,
+        snapshot: Vec<SystemSnapshot>,
+        actor: Option<String>,
+    ) -> Result<SyncSummary, RepoError> {
+        let summary = db::with_transaction(pool, move |conn| {
+            // Row locks would miss systems that don't exist yet, so a concurrent sync of the
+            // same map waits on the map's advisory lock until this one commits
+            diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+                .bind::<diesel::sql_types::Text, _>(map_id.to_string())
+                .execute(conn)?;
+            let existing: HashMap<i64, MapSystem> = map_system_v1::table
+                .filter(map_system_v1::map_id.eq(map_id))
+                .select(MapSystem::as_select())
+                .load::<MapSystem>(conn)?
+                .into_iter()
+                .map(|system| (system.solar_system_id, system))
+                .collect();
+
+            let mut new_rows = Vec::new();
+            let mut updated = Vec::new();
+            for system in &snapshot {
+                match existing.get(&system.solar_system_id) {
+                    None => new_rows.push(system.row(map_id)),
+                    Some(current) if !system.matches(current) => {
+                        diesel::update(map_system_v1::table.find(current.id))
+                            .set((system.row(map_id), map_system_v1::updated_at.eq(now)))
+                            .execute(conn)?;
+                        updated.push(current.id);
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            let mut inserted = Vec::new();
+            // Stays well under the bind parameter limit of a single statement
+            for rows in new_rows.chunks(1000) {
+                inserted.extend(
+                    diesel::insert_into(map_system_v1::table)
+                        .values(rows)
+                        .returning(map_system_v1::id)
+                        .get_results::<Uuid>(conn)?,
+                );
+            }
+
+            let kept: HashSet<i64> = snapshot.iter().map(|s| s.solar_system_id).collect();
+            let removed: Vec<Uuid> = existing
+                .values()
+                .filter(|system| !kept.contains(&system.solar_system_id))
+                .map(|system| system.id)
+                .collect();
+            let signatures = diesel::delete(
+                map_system_signatures_v1::table
+                    .filter(map_system_signatures_v1::system_id.eq_any(&removed)),
+            )
+            .returning(map_system_signatures_v1::id)
+            .get_results::<Uuid>(conn)?;
+            let connections = diesel::delete(
+                map_connection_v1::table.filter(
+                    map_connection_v1::source_system_id
+                        .eq_any(&removed)
+                        .or(map_connection_v1::target_system_id.eq_any(&removed)),
+                ),
+            )
+            .returning(map_connection_v1::id)
+            .get_results::<Uuid>(conn)?;
+            let deleted =
+                diesel::delete(map_system_v1::table.filter(map_system_v1::id.eq_any(&removed)))
+                    .returning(map_system_v1::id)
+                    .get_results::<Uuid>(conn)?;
+
+            let actor = actor.as_deref();
+            AuditRepository::record(conn, "map_system_v1", &inserted, Operation::Create, actor)?;
+            AuditRepository::record(conn, "map_system_v1", &updated, Operation::Update, actor)?;
+            AuditRepository::record(
+                conn,
+                "map_system_signatures_v1",
+                &signatures,
+                Operation::Delete,
+                actor,
+            )?;
+            AuditRepository::record(
+                conn,
+                "map_connection_v1",
+                &connections,
+                Operation::Delete,
+                actor,
+            )?;
+            AuditRepository::record(conn, "map_system_v1", &deleted, Operation::Delete, actor)?;
+
+            Ok(SyncSummary {
+                inserted: inserted.len(),
+                updated: updated.len(),
+                deleted: deleted.len(),
+            })
+        })
+        .await?;
+
+        Ok(summary)
+    }
+}
+
+/// `ILIKE` pattern matching raw label columns that may contain `label`
+fn label_pattern(label: &str) -> String {
+    format!(
+        "%{}%",
+        label
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+fn has_label(labels: &[String], label: &str) -> bool {
+    labels
+        .iter()
+        .any(|candidate| candidate.eq_ignore_ascii_case(label))
+}
+
+pub struct MapSignatureRepository;
+
+impl MapSignatureRepository {
+    /// Load every signature scanned in a single system
+    #[instrument(skip(pool))]
+    pub async fn get_signatures_by_system_id(
+        pool: &DbPool,
+        system_id: Uuid,
+    ) -> Result<Vec<MapSignature>, RepoError> {
+        let signatures = db::run(pool, move |conn| {
+            map_system_signatures_v1::table
+                .filter(map_system_signatures_v1::system_id.eq(system_id))
+                .order(map_system_signatures_v1::eve_id.asc())
+                .select(MapSignature::as_select())
+                .load(conn)
+        })
+        .await?;
+
+        Ok(signatures)
+    }
+}
+
+pub struct MapConnectionRepository;
+
+impl MapConnectionRepository {
+    /// Load every connection on a single map
+    #[instrument(skip(pool))]
+    pub async fn get_connections_by_map_id(
+        pool: &DbPool,
+        map_id: Uuid,
+    ) -> Result<Vec<MapConnection>, RepoError> {
+        let connections = db::run(pool, move |conn| {
+            map_connection_v1::table
+                .filter(map_connection_v1::map_id.eq(map_id))
+                .select(MapConnection::as_select())
+                .load(conn)
+        })
+        .await?;
+
+        Ok(connections)
+    }
+
+    /// Load the systems directly connected to a system, each with its connection, ordered
+    /// by name. Empty for an isolated or unknown system.
+    #[instrument(skip(pool))]
+    pub async fn get_neighbors(pool: &DbPool, system_id: Uuid) -> Result<Vec<Neighbor>, RepoError> {
+        let (connections, systems) = db::run(pool, move |conn| {
+            let connections = map_connection_v1::table
+                .filter(
+                    map_connection_v1::source_system_id
+                        .eq(system_id)
+                        .or(map_connection_v1::target_system_id.eq(system_id)),
+                )
+                .select(MapConnection::as_select())
+                .load::<MapConnection>(conn)?;
+
+            let neighbor_ids: Vec<Uuid> = connections
+                .iter()
+                .map(|connection| other_end(connection, system_id))
+                .collect();
+            let systems = map_system_v1::table
+                .filter(map_system_v1::id.eq_any(neighbor_ids))
+                .order(map_system_v1::name.asc())
+                .select(MapSystem::as_select())
+                .load::<MapSystem>(conn)?;
+
+            Ok::<_, diesel::result::Error>((connections, systems))
+        })
+        .await?;
+
+        // A pair of systems may be linked more than once, e.g. by two wormholes
+        let mut neighbors = Vec::with_capacity(connections.len());
+        for system in &systems {
+            for connection in &connections {
+                if other_end(connection, system_id) == system.id {
+                    neighbors.push(Neighbor {
+                        system: system.clone(),
+                        connection: connection.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(neighbors)
+    }
+}
+
+/// The system at the far end of a connection from `system_id`
+fn other_end(connection: &MapConnection, system_id: Uuid) -> Uuid {
+    if connection.source_system_id == system_id {
+        connection.target_system_id
+    } else {
+        connection.source_system_id
+    }
+}
+
+pub struct AuditRepository;
+
+impl AuditRepository {
+    /// Record `operation` on rows of `table` by `actor`.
+    ///
+    /// Takes the connection of the change it records, so that inside a transaction both
+    /// are committed or neither is.
+    pub fn record<I: ToString>(
+        conn: &mut PgConnection,
+        table: &str,
+        row_ids: &[I],
+        operation: Operation,
+        actor: Option<&str>,
+    ) -> QueryResult<()> {
+        if row_ids.is_empty() {
+            return Ok(());
+        }
+
+        let entries: Vec<_> = row_ids
+            .iter()
+            .map(|row_id| NewAuditEntry {
+                table_name: table,
+                row_id: row_id.to_string(),
+                operation: operation.as_str(),
+                actor,
+            })
+            .collect();
+
+        diesel::insert_into(audit_log::table)
+            .values(&entries)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Load one page of the audit log, oldest first, optionally only the changes to one
+    /// table and those recorded at or after `since`
+    #[instrument(skip(pool))]
+    pub async fn get_entries(
+        pool: &DbPool,
+        table: Option<String>,
+        since: Option<NaiveDateTime>,
+        pagination: Pagination,
+    ) -> Result<Page<AuditEntry>, RepoError> {
+        let limit = pagination.limit();
+        let offset = pagination.offset();
+
+        let (items, total) = db::run(pool, move |conn| {
+            let filtered = || {
+                let mut query = audit_log::table.into_boxed();
+                if let Some(table) = &table {
+                    query = query.filter(audit_log::table_name.eq(table.clone()));
+                }
+                if let Some(since) = since {
+                    query = query.filter(audit_log::recorded_at.ge(since));
+                }
+                query
+            };
+
+            let total = filtered().count().get_result::<i64>(conn)?;
+
+            let items = filtered()
+                .order(audit_log::id.asc())
+                .limit(limit)
+                .offset(offset)
+                .select(AuditEntry::as_select())
+                .load::<AuditEntry>(conn)?;
+
+            Ok::<_, diesel::result::Error>((items, total))
+        })
+        .await?;
+
+        Ok(Page {
+            items,
+            total,
+            limit,
+            offset,
+        })
+    }
+}
@@ -0,0 +1,52 @@
+//! Synthetic notifications for developing against the SSE and WebSocket streams without
+//! a live map. Only built with the `inject-notifications` feature, which is off by default.
+
+use axum::{extract::State, http::StatusCode, routing::post, Router};
+use tracing::instrument;
+use utoipa::OpenApi;
+
+use crate::error::ApiError;
+use crate::extract::ApiJson;
+use crate::notify::{NotifierHandle, SystemNotification};
+use crate::state::AppState;
+
+/// Publish a notification to every stream client as if Postgres had sent it. The body is
+/// a notification as the streams deliver it, and it takes the same path from the listener
+/// onwards, coalescing included. Nothing is written to the database.
+#[utoipa::path(
+    post,
+    path = "/admin/notify",
+    tag = "admin",
+    request_body = SystemNotification,
+    responses(
+        (status = 202, description = "Notification published"),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 422, description = "Not a notification", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+        (status = 503, description = "Listener stopped", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip_all, fields(kind = notification.kind()))]
+pub(crate) async fn inject_notification(
+    State(listener): State<NotifierHandle>,
+    ApiJson(notification): ApiJson<SystemNotification>,
+) -> Result<StatusCode, ApiError> {
+    if !listener.inject(notification) {
+        return Err(ApiError::Unavailable(
+            "notification listener has stopped".to_string(),
+        ));
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Injection routes, to be merged behind authentication
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route("/admin/notify", post(inject_notification))
+}
+
+/// The injection endpoint's part of the OpenAPI document
+#[derive(OpenApi)]
+#[openapi(paths(inject_notification))]
+pub(crate) struct InjectApiDoc;
@@ -0,0 +1,31 @@
+//! Connector API for Wanderer DB: read access to map systems plus live change streams
+//! fed by Postgres LISTEN/NOTIFY.
+
+pub mod admin;
+pub mod auth;
+pub mod cache;
+pub mod chain;
+pub mod config;
+pub mod db;
+pub mod discord;
+pub mod error;
+pub mod event_log;
+pub mod extract;
+#[cfg(feature = "demo-endpoints")]
+mod greeting;
+pub mod handlers;
+#[cfg(feature = "inject-notifications")]
+mod inject;
+pub mod metrics;
+pub mod models;
+pub mod notify;
+mod openapi;
+pub mod otlp;
+mod propagation;
+pub mod rate_limit;
+pub mod routes;
+pub mod schema;
+pub mod sse;
+pub mod state;
+pub mod webhook;
+pub mod ws;
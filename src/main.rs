@@ -1,79 +1,113 @@
-use axum::{
-    extract::Query,
-    response::Json,
-    routing::{get, post},
-    Router,
-};
 use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::{
-    trace::{self},
+    metrics::MeterProvider,
+    propagation::TraceContextPropagator,
+    trace::{self, Sampler},
     Resource,
 };
-use serde::{Deserialize, Serialize};
-use tower::ServiceBuilder;
-use tower_http::trace::TraceLayer;
-use tracing::{info, instrument};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-#[derive(Serialize)]
-struct HealthResponse {
-    status: String,
-    timestamp: u64,
-}
-
-#[derive(Deserialize, Debug)]
-struct QueryParams {
-    name: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct GreetingRequest {
-    name: String,
-}
+use wanderer_connector::auth::ApiKeys;
+use wanderer_connector::cache::SystemCache;
+use wanderer_connector::config::{Config, LogFormat, OtlpProtocol};
+use wanderer_connector::db::tls::DbTls;
+use wanderer_connector::db::{self, establish_connection_pool, establish_read_pool};
+use wanderer_connector::discord::DiscordNotifier;
+use wanderer_connector::event_log::EventLog;
+use wanderer_connector::metrics;
+use wanderer_connector::notify::{NotificationListener, ALL_CHANNELS};
+use wanderer_connector::otlp;
+use wanderer_connector::rate_limit::RateLimiter;
+use wanderer_connector::routes::{cors_layer, create_router};
+use wanderer_connector::state::AppState;
+use wanderer_connector::webhook::WebhookForwarder;
 
-#[derive(Serialize)]
-struct GreetingResponse {
-    message: String,
-}
+/// How long the notification listener gets to close its connection before it is aborted
+const LISTENER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the webhook and Discord forwarders get to deliver what they have queued once
+/// the listener has stopped, before they are aborted
+const FORWARDER_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// Initialize OpenTelemetry tracing
-fn init_tracing() -> Result<(), Box<dyn std::error::Error>> {
+/// Initialize OpenTelemetry tracing.
+///
+/// With `OTEL_REQUIRED=true` a failing OTLP exporter is an error, otherwise logging falls
+/// back to the console only.
+fn init_tracing(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     // First, set up basic tracing subscriber
     let env_filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
         "wanderer_connector=debug,tower_http=debug,axum::rejection=trace".into()
     });
 
+    // Exactly one of these is set; an unset `Option` layer does nothing
+    let json = config.log_format == LogFormat::Json;
+    let pretty_layer = (!json).then(tracing_subscriber::fmt::layer);
+    let json_layer = json.then(|| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+    });
+
+    // Continue traces started by upstream services
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
     // Try to set up OpenTelemetry OTLP exporter
-    match opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(
-            opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint("http://localhost:4317"), // Default OTEL collector endpoint
-        )
-        .with_trace_config(trace::config().with_resource(Resource::new(vec![
-            KeyValue::new("service.name", "wanderer-connector"),
-            KeyValue::new("service.version", "0.1.0"),
-        ])))
-        .install_batch(opentelemetry_sdk::runtime::Tokio)
-    {
+    let trace_config = trace::config()
+        .with_sampler(trace_sampler(config.traces_sampler_ratio))
+        .with_resource(resource(config));
+    let tracer = match config.otlp_protocol {
+        OtlpProtocol::Grpc => opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(config.otlp_endpoint.clone()),
+            )
+            .with_trace_config(trace_config)
+            .install_batch(opentelemetry_sdk::runtime::Tokio),
+        OtlpProtocol::HttpProtobuf => otlp::install_tracer(&config.otlp_endpoint, trace_config),
+    };
+    match tracer {
         Ok(tracer) => {
-            println!("✅ OpenTelemetry initialized successfully, sending traces to http://localhost:4317");
+            println!(
+                "✅ OpenTelemetry initialized successfully, sending traces to {} over {:?} ({})",
+                config.otlp_endpoint,
+                config.otlp_protocol,
+                otel_mode(config)
+            );
             // Set up tracing subscriber with OpenTelemetry layer
             tracing_subscriber::registry()
                 .with(env_filter)
-                .with(tracing_subscriber::fmt::layer())
+                .with(pretty_layer)
+                .with(json_layer)
                 .with(tracing_opentelemetry::layer().with_tracer(tracer))
                 .init();
         }
+        Err(e) if config.otel_required => {
+            return Err(format!(
+                "failed to initialize OpenTelemetry, required by OTEL_REQUIRED: {}",
+                e
+            )
+            .into());
+        }
         Err(e) => {
             println!("⚠️  Failed to initialize OpenTelemetry: {}", e);
-            println!("📝 Falling back to console-only logging");
+            println!(
+                "📝 Falling back to console-only logging ({})",
+                otel_mode(config)
+            );
             // Fall back to console-only logging
             tracing_subscriber::registry()
                 .with(env_filter)
-                .with(tracing_subscriber::fmt::layer())
+                .with(pretty_layer)
+                .with(json_layer)
                 .init();
         }
     }
@@ -81,66 +115,244 @@ fn init_tracing() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-/// Health check endpoint
-#[instrument]
-async fn health() -> Json<HealthResponse> {
-    info!("Health check requested");
-    Json(HealthResponse {
-        status: "healthy".to_string(),
-        timestamp: std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-    })
+fn otel_mode(config: &Config) -> &'static str {
+    if config.otel_required {
+        "OTEL_REQUIRED=true, failures are fatal"
+    } else {
+        "OTEL_REQUIRED=false, failures fall back to the console"
+    }
 }
 
-/// Simple greeting endpoint with query parameters
-#[instrument]
-async fn hello(Query(params): Query<QueryParams>) -> Json<GreetingResponse> {
-    let name = params.name.unwrap_or_else(|| "World".to_string());
-    info!("Greeting requested for: {}", name);
+/// Export OpenTelemetry metrics over the same OTLP endpoint as traces and install the
+/// provider globally.
+///
+/// Returns `None` when the exporter cannot be set up and OpenTelemetry is not required;
+/// the instruments then record into the no-op global provider.
+fn init_metrics(config: &Config) -> Result<Option<MeterProvider>, Box<dyn std::error::Error>> {
+    let provider = match config.otlp_protocol {
+        OtlpProtocol::Grpc => opentelemetry_otlp::new_pipeline()
+            .metrics(opentelemetry_sdk::runtime::Tokio)
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(config.otlp_endpoint.clone()),
+            )
+            .with_resource(resource(config))
+            .build(),
+        OtlpProtocol::HttpProtobuf => {
+            otlp::install_meter_provider(&config.otlp_endpoint, resource(config))
+        }
+    };
+    match provider {
+        Ok(provider) => {
+            info!(
+                "Exporting OpenTelemetry metrics to {}",
+                config.otlp_endpoint
+            );
+            Ok(Some(provider))
+        }
+        Err(e) if config.otel_required => Err(format!(
+            "failed to initialize OpenTelemetry metrics, required by OTEL_REQUIRED: {}",
+            e
+        )
+        .into()),
+        Err(e) => {
+            warn!(
+                "Failed to initialize OpenTelemetry metrics, continuing without them: {}",
+                e
+            );
+            Ok(None)
+        }
+    }
+}
 
-    Json(GreetingResponse {
-        message: format!("Hello, {}!", name),
-    })
+fn resource(config: &Config) -> Resource {
+    Resource::new(vec![
+        KeyValue::new("service.name", config.service_name.clone()),
+        KeyValue::new("service.version", "0.1.0"),
+    ])
 }
 
-/// Greeting endpoint with JSON body
-#[instrument]
-async fn greet_json(Json(payload): Json<GreetingRequest>) -> Json<GreetingResponse> {
-    info!("JSON greeting requested for: {}", payload.name);
+/// Sample root spans at `OTEL_TRACES_SAMPLER_ARG` (always on by default) while keeping the
+/// decision of a sampled or unsampled parent
+fn trace_sampler(ratio: Option<f64>) -> Sampler {
+    let root = match ratio {
+        Some(ratio) => Sampler::TraceIdRatioBased(ratio),
+        None => Sampler::AlwaysOn,
+    };
 
-    Json(GreetingResponse {
-        message: format!("Hello, {}! (from JSON)", payload.name),
-    })
+    Sampler::ParentBased(Box::new(root))
 }
 
-/// Create the Axum router with all routes
-fn create_router() -> Router {
-    Router::new()
-        .route("/health", get(health))
-        .route("/hello", get(hello))
-        .route("/greet", post(greet_json))
-        .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()))
+/// Resolve once SIGINT (Ctrl+C) or SIGTERM is received
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Fail fast on missing or invalid settings, before anything is started
+    let config = Config::from_env()?;
+
     // Initialize tracing
-    init_tracing()?;
+    init_tracing(&config)?;
+    // Before anything records, so the instruments bind to the OTLP provider
+    let meter_provider = init_metrics(&config)?;
 
     info!("Starting wanderer-connector API server");
 
+    // Install the Prometheus recorder before any metrics are recorded
+    let metrics_handle = metrics::install()?;
+
+    // Set up the database connection pool
+    let tls = DbTls::from_env()?;
+    let pool = establish_connection_pool(&config, tls.as_ref())?;
+    let read_pool = establish_read_pool(&config, tls.as_ref(), &pool)?;
+
+    // Opt-in, so pointing at a shared Wanderer database never alters it by accident
+    if config.run_migrations {
+        db::run_migrations(&pool).await?;
+    }
+
+    // Subscribe to map system changes
+    let notifier = Arc::new(
+        NotificationListener::builder(&config.database_url)
+            .channels(ALL_CHANNELS)
+            .capacity(1024)
+            .coalesce_window(Duration::from_millis(500))
+            .watched_system_columns(&config.system_update_columns)
+            .unhealthy_after(config.ready_listener_down)
+            .mode(config.notify_mode)
+            .poll_interval(config.notify_poll_interval)
+            .tls(tls)
+            .connect()
+            .await?,
+    );
+
+    // Serve map system pages from memory until their map changes
+    let system_cache = SystemCache::new();
+    let cache_task = system_cache.clone().spawn(notifier.subscribe());
+
+    // Number events and keep the latest for SSE clients resuming with Last-Event-Id
+    let event_log = EventLog::new(1024);
+    let event_log_task = event_log.clone().spawn(notifier.subscribe());
+
+    // Optionally push every change to an external endpoint
+    let webhook =
+        WebhookForwarder::from_env()?.map(|forwarder| forwarder.spawn(notifier.subscribe()));
+
+    // Optionally post chain alerts to Discord
+    let discord = DiscordNotifier::from_env()?
+        .map(|discord| discord.spawn(pool.clone(), notifier.subscribe()));
+
     // Create the router
-    let app = create_router();
+    let state = AppState {
+        pool,
+        read_pool,
+        notifier: notifier.clone(),
+        system_cache,
+        event_log,
+        lag_policy: config.lag_policy,
+        stream_keep_alive: config.stream_keep_alive,
+        metrics: metrics_handle,
+    };
+    let app = create_router(
+        state,
+        ApiKeys::new(config.api_keys.clone()),
+        RateLimiter::from_env()?,
+        cors_layer()?,
+        config.max_request_body_bytes,
+    );
 
     // Start the server
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    info!("Server listening on http://0.0.0.0:3000");
+    let grace_period = config.shutdown_grace_period;
+    let listener = tokio::net::TcpListener::bind(config.bind_addr).await?;
+    info!("Server listening on http://{}", listener.local_addr()?);
 
-    axum::serve(listener, app).await?;
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let mut server = tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move {
+            let _ = stop_rx.await;
+        })
+        .await
+    });
 
-    // Shutdown OpenTelemetry
+    tokio::select! {
+        result = &mut server => {
+            result??;
+        }
+        _ = shutdown_signal() => {
+            info!(
+                "Shutting down, waiting up to {:?} for in-flight requests",
+                grace_period
+            );
+            let _ = stop_tx.send(());
+
+            match tokio::time::timeout(grace_period, &mut server).await {
+                Ok(result) => {
+                    result??;
+                    info!("All in-flight requests completed");
+                }
+                Err(_) => {
+                    warn!("Grace period elapsed, dropping remaining connections");
+                    server.abort();
+                }
+            }
+        }
+    }
+
+    // Stop listening for notifications, without letting a stuck connection hold up exit
+    notifier.shutdown(LISTENER_SHUTDOWN_TIMEOUT).await;
+    cache_task.abort();
+    event_log_task.abort();
+
+    // The channel closes once the listener has stopped, which lets the forwarders flush
+    // their queues and partial batches and end by themselves
+    let drain_deadline = tokio::time::Instant::now() + FORWARDER_DRAIN_TIMEOUT;
+    for (name, task) in [("Webhook", webhook), ("Discord", discord)] {
+        let Some(mut task) = task else { continue };
+        if tokio::time::timeout_at(drain_deadline, &mut task)
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} forwarder did not drain within {:?}, aborted it",
+                name, FORWARDER_DRAIN_TIMEOUT
+            );
+            task.abort();
+        }
+    }
+
+    // Shutdown OpenTelemetry, flushing the last metrics and spans
+    if let Some(provider) = meter_provider {
+        if let Err(e) = provider.shutdown() {
+            warn!("Failed to shut down the meter provider: {}", e);
+        }
+    }
     global::shutdown_tracer_provider();
 
     Ok(())
@@ -0,0 +1,150 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use ::metrics::{counter, gauge, histogram};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use opentelemetry::metrics::{Counter, Histogram, Unit};
+use opentelemetry::{global, KeyValue};
+
+use crate::db::DbPool;
+
+const REQUEST_DURATION_METRIC: &str = "http_request_duration_seconds";
+
+/// Latency buckets in seconds, from a cache hit up to a slow chain computation
+const REQUEST_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+/// OpenTelemetry instruments exported over OTLP next to the Prometheus metrics
+pub struct Instruments {
+    pub http_requests: Counter<u64>,
+    pub http_request_duration: Histogram<f64>,
+    pub db_query_duration: Histogram<f64>,
+    pub db_statement_duration: Histogram<f64>,
+    pub db_pool_acquire_duration: Histogram<f64>,
+    pub db_pool_slow_acquires: Counter<u64>,
+    pub notifications_received: Counter<u64>,
+    pub notifications_forwarded: Counter<u64>,
+    pub notifications_dropped: Counter<u64>,
+}
+
+impl Instruments {
+    fn new() -> Self {
+        let meter = global::meter("wanderer-connector");
+
+        Self {
+            http_requests: meter
+                .u64_counter("http.server.requests")
+                .with_description("Routed HTTP requests")
+                .init(),
+            http_request_duration: meter
+                .f64_histogram("http.server.duration")
+                .with_description("Time to produce an HTTP response")
+                .with_unit(Unit::new("s"))
+                .init(),
+            db_query_duration: meter
+                .f64_histogram("db.query.duration")
+                .with_description("Time for a database query, retries included")
+                .with_unit(Unit::new("s"))
+                .init(),
+            db_statement_duration: meter
+                .f64_histogram("db.statement.duration")
+                .with_description("Time a query attempt ran, without waiting for a connection")
+                .with_unit(Unit::new("s"))
+                .init(),
+            db_pool_acquire_duration: meter
+                .f64_histogram("db.pool.acquire.duration")
+                .with_description("Time spent waiting to check out a pooled connection")
+                .with_unit(Unit::new("s"))
+                .init(),
+            db_pool_slow_acquires: meter
+                .u64_counter("db.pool.slow_acquires")
+                .with_description("Connection checkouts that waited past DB_SLOW_ACQUIRE_MS")
+                .init(),
+            notifications_received: meter
+                .u64_counter("notifications.received")
+                .with_description("Notifications received from Postgres")
+                .init(),
+            notifications_forwarded: meter
+                .u64_counter("notifications.forwarded")
+                .with_description("Notifications handed to subscribers")
+                .init(),
+            notifications_dropped: meter
+                .u64_counter("notifications.dropped")
+                .with_description("Notifications that reached no subscriber")
+                .init(),
+        }
+    }
+}
+
+/// The process-wide instruments.
+///
+/// Instruments are bound to the meter provider installed when they are first used, so
+/// this must not be called before the OTLP pipeline is set up.
+pub fn instruments() -> &'static Instruments {
+    INSTRUMENTS.get_or_init(Instruments::new)
+}
+
+/// Install the global Prometheus recorder and return a handle for rendering it
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(REQUEST_DURATION_METRIC.to_string()),
+            REQUEST_DURATION_BUCKETS,
+        )?
+        .install_recorder()
+}
+
+/// Record a request count and latency for every routed request, labelled by route template
+pub async fn track_metrics(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+
+    let response = next.run(request).await;
+
+    let labels = [
+        ("method", method),
+        ("path", path),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    let elapsed = start.elapsed().as_secs_f64();
+    counter!("http_requests_total", &labels).increment(1);
+    histogram!(REQUEST_DURATION_METRIC, &labels).record(elapsed);
+
+    let attributes = labels.map(|(key, value)| KeyValue::new(key, value));
+    let instruments = instruments();
+    instruments.http_requests.add(1, &attributes);
+    instruments
+        .http_request_duration
+        .record(elapsed, &attributes);
+
+    response
+}
+
+/// Prometheus scrape endpoint
+pub async fn metrics_handler(
+    State(pool): State<DbPool>,
+    State(handle): State<PrometheusHandle>,
+) -> impl IntoResponse {
+    // Pool gauges are sampled at scrape time rather than tracked on every checkout
+    let state = pool.state();
+    gauge!("db_pool_connections").set(state.connections as f64);
+    gauge!("db_pool_idle_connections").set(state.idle_connections as f64);
+    gauge!("db_pool_in_use_connections")
+        .set(state.connections.saturating_sub(state.idle_connections) as f64);
+    gauge!("db_pool_max_size").set(pool.max_size() as f64);
+
+    handle.render()
+}
@@ -0,0 +1,470 @@
+use std::ops::Range;
+
+use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::pg::{Pg, PgValue};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Nullable, Text};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::schema::{audit_log, map_connection_v1, map_system_signatures_v1, map_system_v1};
+
+pub const DEFAULT_PAGE_LIMIT: i64 = 100;
+pub const MAX_PAGE_LIMIT: i64 = 500;
+
+/// `limit`/`offset` query parameters for list endpoints
+#[derive(Deserialize, IntoParams, Debug, Clone, Copy, Default)]
+#[into_params(parameter_in = Query)]
+pub struct Pagination {
+    /// Page size, 1 to 500 (default 100)
+    pub limit: Option<i64>,
+    /// Number of rows to skip (default 0)
+    pub offset: Option<i64>,
+}
+
+impl Pagination {
+    /// Requested page size, capped at [`MAX_PAGE_LIMIT`]
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+/// One page of a list endpoint along with the total number of matching rows
+#[derive(Serialize, ToSchema, Debug, Clone)]
+#[aliases(MapSystemPage = Page<MapSystem>, AuditEntryPage = Page<AuditEntry>)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// How many systems a map has and when the most recent of them changed. Together they
+/// change whenever a system on the map is added, updated or removed.
+pub type MapVersion = (i64, Option<NaiveDateTime>);
+
+/// EVE solar system ids: known space from 30000000, wormhole space from 31000000.
+/// Anything past it, such as abyssal pockets, cannot be placed on a map.
+pub const SOLAR_SYSTEM_IDS: Range<i64> = 30_000_000..32_000_000;
+
+const WORMHOLE_SYSTEM_IDS: Range<i64> = 31_000_000..32_000_000;
+
+/// Whether `solar_system_id` could be a real system on a map
+pub fn is_valid_solar_system_id(solar_system_id: i64) -> bool {
+    SOLAR_SYSTEM_IDS.contains(&solar_system_id)
+}
+
+/// Whether `solar_system_id` is in wormhole space
+pub fn is_wormhole_system(solar_system_id: i64) -> bool {
+    WORMHOLE_SYSTEM_IDS.contains(&solar_system_id)
+}
+
+/// A solar system placed on a Wanderer map
+#[derive(Queryable, Selectable, Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+#[diesel(table_name = map_system_v1)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[serde(from = "StoredSystem")]
+pub struct MapSystem {
+    pub id: Uuid,
+    pub map_id: Uuid,
+    pub solar_system_id: i64,
+    pub name: String,
+    pub custom_name: Option<String>,
+    pub description: Option<String>,
+    pub tag: Option<String>,
+    /// Operational tags such as `staging`, parsed from the raw `labels` column
+    #[diesel(deserialize_as = LabelColumn)]
+    pub labels: Vec<String>,
+    pub status: i64,
+    pub visible: bool,
+    pub locked: bool,
+    pub position_x: i64,
+    pub position_y: i64,
+    pub inserted_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    /// Whether the system is in wormhole space, derived from `solar_system_id`
+    #[diesel(
+        select_expression = map_system_v1::solar_system_id,
+        select_expression_type = map_system_v1::solar_system_id,
+        deserialize_as = WormholeColumn
+    )]
+    pub wormhole: bool,
+}
+
+/// A systems row as stored, which is what the notify triggers send. [`MapSystem`] adds
+/// the fields derived from it.
+#[derive(Deserialize)]
+struct StoredSystem {
+    id: Uuid,
+    map_id: Uuid,
+    solar_system_id: i64,
+    name: String,
+    custom_name: Option<String>,
+    description: Option<String>,
+    tag: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_labels")]
+    labels: Vec<String>,
+    status: i64,
+    visible: bool,
+    locked: bool,
+    position_x: i64,
+    position_y: i64,
+    inserted_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+}
+
+impl From<StoredSystem> for MapSystem {
+    fn from(row: StoredSystem) -> Self {
+        MapSystem {
+            id: row.id,
+            map_id: row.map_id,
+            solar_system_id: row.solar_system_id,
+            name: row.name,
+            custom_name: row.custom_name,
+            description: row.description,
+            tag: row.tag,
+            labels: row.labels,
+            status: row.status,
+            visible: row.visible,
+            locked: row.locked,
+            position_x: row.position_x,
+            position_y: row.position_y,
+            inserted_at: row.inserted_at,
+            updated_at: row.updated_at,
+            wormhole: is_wormhole_system(row.solar_system_id),
+        }
+    }
+}
+
+/// `solar_system_id` read a second time, into [`MapSystem::wormhole`]
+#[derive(FromSqlRow)]
+pub struct WormholeColumn(bool);
+
+impl From<WormholeColumn> for bool {
+    fn from(column: WormholeColumn) -> Self {
+        column.0
+    }
+}
+
+impl FromSql<BigInt, Pg> for WormholeColumn {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let solar_system_id = <i64 as FromSql<BigInt, Pg>>::from_sql(bytes)?;
+        Ok(WormholeColumn(is_wormhole_system(solar_system_id)))
+    }
+}
+
+/// A system as it should be on a map, one entry of a sync snapshot. Omitted fields take
+/// the same defaults as a new row.
+#[derive(Deserialize, ToSchema, Debug, Clone)]
+pub struct SystemSnapshot {
+    pub solar_system_id: i64,
+    pub name: String,
+    pub custom_name: Option<String>,
+    pub description: Option<String>,
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(default)]
+    pub status: i64,
+    #[serde(default = "default_visible")]
+    pub visible: bool,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub position_x: i64,
+    #[serde(default)]
+    pub position_y: i64,
+}
+
+fn default_visible() -> bool {
+    true
+}
+
+impl SystemSnapshot {
+    /// Whether `system` already matches the snapshot, so syncing would not change it
+    pub fn matches(&self, system: &MapSystem) -> bool {
+        self.solar_system_id == system.solar_system_id
+            && self.name == system.name
+            && self.custom_name == system.custom_name
+            && self.description == system.description
+            && self.tag == system.tag
+            && self.labels == system.labels
+            && self.status == system.status
+            && self.visible == system.visible
+            && self.locked == system.locked
+            && self.position_x == system.position_x
+            && self.position_y == system.position_y
+    }
+
+    pub(crate) fn row(&self, map_id: Uuid) -> SystemRow<'_> {
+        SystemRow {
+            map_id,
+            solar_system_id: self.solar_system_id,
+            name: &self.name,
+            custom_name: self.custom_name.as_deref(),
+            description: self.description.as_deref(),
+            tag: self.tag.as_deref(),
+            // Stored as a JSON array, one of the forms `parse_labels` reads back
+            labels: (!self.labels.is_empty())
+                .then(|| serde_json::Value::from(self.labels.clone()).to_string()),
+            status: self.status,
+            visible: self.visible,
+            locked: self.locked,
+            position_x: self.position_x,
+            position_y: self.position_y,
+        }
+    }
+}
+
+/// The columns a sync writes; the id and timestamps are left to the database
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = map_system_v1)]
+#[diesel(treat_none_as_null = true)]
+pub(crate) struct SystemRow<'a> {
+    pub map_id: Uuid,
+    pub solar_system_id: i64,
+    pub name: &'a str,
+    pub custom_name: Option<&'a str>,
+    pub description: Option<&'a str>,
+    pub tag: Option<&'a str>,
+    pub labels: Option<String>,
+    pub status: i64,
+    pub visible: bool,
+    pub locked: bool,
+    pub position_x: i64,
+    pub position_y: i64,
+}
+
+/// What reconciling a map to a snapshot changed
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SyncSummary {
+    /// Systems in the snapshot that were not on the map
+    pub inserted: usize,
+    /// Systems on the map that differed from the snapshot
+    pub updated: usize,
+    /// Systems on the map that were missing from the snapshot
+    pub deleted: usize,
+}
+
+/// Split a raw `labels` value into its labels. Wanderer has stored them as a JSON array,
+/// as a JSON object with a `labels` array and as a comma-separated list; anything else,
+/// including an empty string, yields no labels.
+pub fn parse_labels(raw: &str) -> Vec<String> {
+    let raw = raw.trim();
+    let json = raw
+        .starts_with(['[', '{'])
+        .then(|| serde_json::from_str::<serde_json::Value>(raw).ok())
+        .flatten();
+
+    let labels: Vec<&str> = match &json {
+        Some(value) => value
+            .get("labels")
+            .unwrap_or(value)
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(serde_json::Value::as_str)
+            .collect(),
+        None => raw.split(',').collect(),
+    };
+
+    labels
+        .into_iter()
+        .map(str::trim)
+        .filter(|label| !label.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The nullable `labels` column, read into [`MapSystem::labels`]
+#[derive(FromSqlRow)]
+pub struct LabelColumn(Vec<String>);
+
+impl From<LabelColumn> for Vec<String> {
+    fn from(column: LabelColumn) -> Self {
+        column.0
+    }
+}
+
+impl FromSql<Nullable<Text>, Pg> for LabelColumn {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let raw = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(LabelColumn(parse_labels(&raw)))
+    }
+
+    fn from_nullable_sql(bytes: Option<PgValue<'_>>) -> deserialize::Result<Self> {
+        match bytes {
+            Some(bytes) => Self::from_sql(bytes),
+            None => Ok(LabelColumn(Vec::new())),
+        }
+    }
+}
+
+/// Accepts labels already split into a list as well as the raw column, which is what
+/// the notify triggers send
+fn deserialize_labels<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawLabels {
+        List(Vec<String>),
+        Column(String),
+    }
+
+    Ok(match Option::<RawLabels>::deserialize(deserializer)? {
+        Some(RawLabels::List(labels)) => labels,
+        Some(RawLabels::Column(raw)) => parse_labels(&raw),
+        None => Vec::new(),
+    })
+}
+
+/// A cosmic signature scanned down in a map system
+#[derive(Queryable, Selectable, Serialize, Deserialize, ToSchema, Debug, Clone)]
+#[diesel(table_name = map_system_signatures_v1)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MapSignature {
+    pub id: Uuid,
+    pub system_id: Uuid,
+    pub eve_id: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub kind: Option<String>,
+    #[schema(value_type = Option<String>, example = "wormhole")]
+    pub group: Option<SignatureGroup>,
+    pub linked_system_id: Option<i64>,
+    pub inserted_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A system one connection away from another, with the connection between them
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct Neighbor {
+    pub system: MapSystem,
+    pub connection: MapConnection,
+}
+
+/// What a signature turned out to be once scanned. Groups this crate does not know keep
+/// the raw string, so new ones added by Wanderer pass through untouched.
+#[derive(FromSqlRow, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SignatureGroup {
+    Data,
+    Relic,
+    Gas,
+    Wormhole,
+    Combat,
+    Unknown(String),
+}
+
+impl SignatureGroup {
+    pub fn as_str(&self) -> &str {
+        match self {
+            SignatureGroup::Data => "data",
+            SignatureGroup::Relic => "relic",
+            SignatureGroup::Gas => "gas",
+            SignatureGroup::Wormhole => "wormhole",
+            SignatureGroup::Combat => "combat",
+            SignatureGroup::Unknown(raw) => raw,
+        }
+    }
+}
+
+impl From<String> for SignatureGroup {
+    /// Accepts both our names and Wanderer's labels such as `Data Site`, in any case
+    fn from(raw: String) -> Self {
+        let normalized = raw.trim().to_lowercase();
+        match normalized.strip_suffix(" site").unwrap_or(&normalized) {
+            "data" => SignatureGroup::Data,
+            "relic" => SignatureGroup::Relic,
+            "gas" => SignatureGroup::Gas,
+            "wormhole" => SignatureGroup::Wormhole,
+            "combat" => SignatureGroup::Combat,
+            _ => SignatureGroup::Unknown(raw),
+        }
+    }
+}
+
+impl FromSql<Text, Pg> for SignatureGroup {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let raw = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(raw.into())
+    }
+}
+
+impl Serialize for SignatureGroup {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for SignatureGroup {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Into::into)
+    }
+}
+
+/// A wormhole connection between two systems on the same map
+#[derive(Queryable, Selectable, Serialize, Deserialize, ToSchema, Debug, Clone)]
+#[diesel(table_name = map_connection_v1)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MapConnection {
+    pub id: Uuid,
+    pub map_id: Uuid,
+    pub source_system_id: Uuid,
+    pub target_system_id: Uuid,
+    pub mass_status: i64,
+    pub time_status: i64,
+    pub inserted_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// What was done to an audited row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Create,
+    Update,
+    Delete,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+        }
+    }
+}
+
+/// One change recorded in the audit log
+#[derive(Queryable, Selectable, Serialize, ToSchema, Debug, Clone)]
+#[diesel(table_name = audit_log)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuditEntry {
+    pub id: i64,
+    /// Table of the changed row, e.g. `map_system_v1`
+    pub table_name: String,
+    pub row_id: String,
+    /// `create`, `update` or `delete`
+    pub operation: String,
+    /// Who made the change, when the request was authenticated
+    pub actor: Option<String>,
+    pub recorded_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = audit_log)]
+pub(crate) struct NewAuditEntry<'a> {
+    pub table_name: &'a str,
+    pub row_id: String,
+    pub operation: &'static str,
+    pub actor: Option<&'a str>,
+}
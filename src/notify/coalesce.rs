@@ -0,0 +1,113 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use opentelemetry::KeyValue;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep_until, Instant};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::metrics::instruments;
+use crate::models::MapSystem;
+
+use super::maps::MapChannels;
+use super::SystemNotification;
+
+/// An update held back while its window is open
+struct Held {
+    old: Option<Box<MapSystem>>,
+    new: MapSystem,
+}
+
+impl From<Held> for SystemNotification {
+    fn from(held: Held) -> Self {
+        SystemNotification::Update {
+            old: held.old,
+            new: held.new,
+        }
+    }
+}
+
+/// Forward notifications from `incoming` to all subscribers and to those of the map they
+/// belong to, collapsing the system updates that arrive for one system within `window`
+/// into one going from the first update's old row to the latest new row.
+///
+/// The first update for a system opens its window; every other notification passes
+/// through immediately. A held update is flushed early when another notification for the
+/// same system arrives, so subscribers never see changes out of order. Returns once every
+/// sender is gone and the held updates are flushed.
+pub(super) async fn run(
+    mut incoming: mpsc::UnboundedReceiver<SystemNotification>,
+    sender: broadcast::Sender<SystemNotification>,
+    maps: MapChannels,
+    window: Duration,
+) {
+    // Merged update per system, with the deadline that releases it
+    let mut held: HashMap<Uuid, (Instant, Held)> = HashMap::new();
+    // Deadlines in the order they were opened; the window is fixed so they are sorted
+    let mut deadlines: VecDeque<(Instant, Uuid)> = VecDeque::new();
+
+    loop {
+        let next_deadline = deadlines.front().map(|(deadline, _)| *deadline);
+
+        tokio::select! {
+            notification = incoming.recv() => match notification {
+                Some(SystemNotification::Update { old, new }) if !window.is_zero() => {
+                    match held.get_mut(&new.id) {
+                        Some((_, update)) => {
+                            debug!("Coalescing update for system {}", new.id);
+                            update.new = new;
+                        }
+                        None => {
+                            let deadline = Instant::now() + window;
+                            deadlines.push_back((deadline, new.id));
+                            held.insert(new.id, (deadline, Held { old, new }));
+                        }
+                    }
+                }
+                Some(notification) => {
+                    if let Some((_, update)) =
+                        notification.system_id().and_then(|id| held.remove(&id))
+                    {
+                        forward(&sender, &maps, update.into());
+                    }
+                    forward(&sender, &maps, notification);
+                }
+                None => break,
+            },
+            _ = sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                let (deadline, id) = deadlines.pop_front().expect("pending deadline");
+
+                // Updates flushed early leave their deadline behind
+                let due = held.get(&id).is_some_and(|(held_until, _)| *held_until == deadline);
+                if due {
+                    let (_, update) = held.remove(&id).expect("held update");
+                    forward(&sender, &maps, update.into());
+                }
+            }
+        }
+    }
+
+    for (_, id) in deadlines {
+        if let Some((_, update)) = held.remove(&id) {
+            forward(&sender, &maps, update.into());
+        }
+    }
+}
+
+/// Broadcast one notification, counting those that no subscriber was listening for
+fn forward(
+    sender: &broadcast::Sender<SystemNotification>,
+    maps: &MapChannels,
+    notification: SystemNotification,
+) {
+    let instruments = instruments();
+    maps.publish(&notification);
+
+    match sender.send(notification) {
+        Ok(_) => instruments.notifications_forwarded.add(1, &[]),
+        Err(_) => instruments
+            .notifications_dropped
+            .add(1, &[KeyValue::new("reason", "no_subscribers")]),
+    }
+}
@@ -0,0 +1,205 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use crate::models::MapConnection;
+
+use super::payload::{NotificationPayload, Operation};
+use super::tables::NotifyTables;
+use super::{
+    NotificationError, SystemNotification, CONNECTION_DELETE_CHANNEL, CONNECTION_INSERT_CHANNEL,
+    CONNECTION_UPDATE_CHANNEL, SIGNATURE_DELETE_CHANNEL, SIGNATURE_INSERT_CHANNEL,
+    SIGNATURE_UPDATE_CHANNEL, SYSTEM_DELETE_CHANNEL, SYSTEM_INSERT_CHANNEL, SYSTEM_UPDATE_CHANNEL,
+};
+
+/// The tables whose rows may have to be loaded after their notification
+#[derive(Debug, Clone, Copy)]
+enum Table {
+    System,
+    Signature,
+    Connection,
+}
+
+impl Table {
+    fn name(self) -> &'static str {
+        match self {
+            Table::System => "systems",
+            Table::Signature => "signatures",
+            Table::Connection => "connections",
+        }
+    }
+
+    // The id is passed as text so no uuid support is needed in tokio-postgres
+    fn select_sql(self) -> String {
+        format!(
+            "SELECT row_to_json(t)::text FROM {{{}}} t WHERE t.id = $1::text::uuid",
+            self.name()
+        )
+    }
+
+    /// Load a row as it is now, or `None` if it has been deleted since
+    async fn load<T: DeserializeOwned>(
+        self,
+        client: &Client,
+        tables: &NotifyTables,
+        id: Uuid,
+    ) -> Result<Option<T>, NotificationError> {
+        let Some(row) = client
+            .query_opt(&tables.render(&self.select_sql()), &[&id.to_string()])
+            .await?
+        else {
+            return Ok(None);
+        };
+        let payload: String = row.get(0);
+
+        serde_json::from_str(&payload)
+            .map(Some)
+            .map_err(|source| NotificationError::InvalidRow {
+                table: self.name(),
+                source,
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Op {
+    Insert,
+    Update,
+}
+
+/// Key of an inserted or updated row, the only thing its trigger sends
+#[derive(Deserialize)]
+struct ChangedRow {
+    id: Uuid,
+}
+
+/// The only part of a deleted signature row we care about
+#[derive(Deserialize)]
+struct DeletedSignatureRow {
+    id: Uuid,
+    system_id: Uuid,
+}
+
+/// A change as announced by a trigger, before the changed row is loaded.
+///
+/// System changes arrive as a [`NotificationPayload`], which only needs loading when the
+/// row was too wide to include. Signature and connection inserts and updates carry just
+/// the row's id, keeping the payload well under the 8000 byte NOTIFY limit. Their deletes
+/// carry the keys consumers need, since the row cannot be loaded once it is gone.
+#[derive(Debug)]
+pub(super) enum PendingChange {
+    System(Box<NotificationPayload>),
+    Signature { op: Op, id: Uuid },
+    Connection { op: Op, id: Uuid },
+    Ready(Box<SystemNotification>),
+}
+
+impl PendingChange {
+    /// Parse a trigger payload according to the channel it arrived on
+    pub(super) fn parse(channel: &str, payload: &str) -> Result<Self, NotificationError> {
+        let invalid = |source| NotificationError::InvalidPayload {
+            channel: channel.to_string(),
+            source,
+        };
+        let changed_id = || {
+            serde_json::from_str::<ChangedRow>(payload)
+                .map(|row| row.id)
+                .map_err(invalid)
+        };
+
+        match channel {
+            SYSTEM_INSERT_CHANNEL | SYSTEM_UPDATE_CHANNEL | SYSTEM_DELETE_CHANNEL => {
+                serde_json::from_str(payload)
+                    .map(|payload| PendingChange::System(Box::new(payload)))
+                    .map_err(invalid)
+            }
+            SIGNATURE_INSERT_CHANNEL => Ok(PendingChange::Signature {
+                op: Op::Insert,
+                id: changed_id()?,
+            }),
+            SIGNATURE_UPDATE_CHANNEL => Ok(PendingChange::Signature {
+                op: Op::Update,
+                id: changed_id()?,
+            }),
+            SIGNATURE_DELETE_CHANNEL => serde_json::from_str::<DeletedSignatureRow>(payload)
+                .map(|row| {
+                    PendingChange::Ready(Box::new(SystemNotification::SignatureDelete {
+                        id: row.id,
+                        system_id: row.system_id,
+                    }))
+                })
+                .map_err(invalid),
+            CONNECTION_INSERT_CHANNEL => Ok(PendingChange::Connection {
+                op: Op::Insert,
+                id: changed_id()?,
+            }),
+            CONNECTION_UPDATE_CHANNEL => Ok(PendingChange::Connection {
+                op: Op::Update,
+                id: changed_id()?,
+            }),
+            CONNECTION_DELETE_CHANNEL => serde_json::from_str::<MapConnection>(payload)
+                .map(|connection| {
+                    PendingChange::Ready(Box::new(SystemNotification::ConnectionDelete(connection)))
+                })
+                .map_err(invalid),
+            other => Err(NotificationError::UnknownChannel(other.to_string())),
+        }
+    }
+
+    /// Turn the change into a notification, loading the row where the payload lacks it.
+    /// Returns `None` if the row was deleted before it could be read; its delete
+    /// notification follows.
+    pub(super) async fn resolve(
+        self,
+        client: &Client,
+        tables: &NotifyTables,
+    ) -> Result<Option<SystemNotification>, NotificationError> {
+        match self {
+            PendingChange::Ready(notification) => Ok(Some(*notification)),
+            PendingChange::System(payload) => resolve_system(client, tables, *payload).await,
+            PendingChange::Signature { op, id } => Ok(Table::Signature
+                .load(client, tables, id)
+                .await?
+                .map(|signature| match op {
+                    Op::Insert => SystemNotification::SignatureInsert(signature),
+                    Op::Update => SystemNotification::SignatureUpdate(signature),
+                })),
+            PendingChange::Connection { op, id } => Ok(Table::Connection
+                .load(client, tables, id)
+                .await?
+                .map(|connection| match op {
+                    Op::Insert => SystemNotification::ConnectionInsert(connection),
+                    Op::Update => SystemNotification::ConnectionUpdate(connection),
+                })),
+        }
+    }
+}
+
+async fn resolve_system(
+    client: &Client,
+    tables: &NotifyTables,
+    payload: NotificationPayload,
+) -> Result<Option<SystemNotification>, NotificationError> {
+    let new = match (payload.op, payload.new) {
+        (Operation::Delete, _) => {
+            return Ok(Some(SystemNotification::Delete {
+                id: payload.id,
+                map_id: payload.map_id,
+            }))
+        }
+        (_, Some(new)) => new,
+        (_, None) => match Table::System.load(client, tables, payload.id).await? {
+            Some(new) => new,
+            None => return Ok(None),
+        },
+    };
+
+    Ok(Some(match payload.op {
+        Operation::Insert => SystemNotification::Insert(new),
+        _ => SystemNotification::Update {
+            old: payload.old.map(Box::new),
+            new,
+        },
+    }))
+}
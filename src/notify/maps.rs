@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use super::SystemNotification;
+
+/// Broadcast channels for single maps, so a subscriber interested in one map never sees
+/// the traffic of the others.
+///
+/// A map's channel is created by its first subscriber and removed once a notification
+/// for the map finds no subscriber left.
+#[derive(Clone)]
+pub(super) struct MapChannels {
+    capacity: usize,
+    channels: Arc<Mutex<HashMap<Uuid, broadcast::Sender<SystemNotification>>>>,
+    /// The ids of closed maps, for subscribers following several maps on one channel
+    closed: broadcast::Sender<Uuid>,
+}
+
+impl MapChannels {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            channels: Arc::default(),
+            closed: broadcast::channel(capacity).0,
+        }
+    }
+
+    pub(super) fn subscribe(&self, map_id: Uuid) -> broadcast::Receiver<SystemNotification> {
+        let mut channels = self.channels.lock().expect("map channels lock poisoned");
+        channels
+            .entry(map_id)
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .subscribe()
+    }
+
+    pub(super) fn subscribe_closed(&self) -> broadcast::Receiver<Uuid> {
+        self.closed.subscribe()
+    }
+
+    /// Drop a map's channel, ending the streams of its subscribers, and announce the map
+    /// as closed
+    pub(super) fn close(&self, map_id: Uuid) {
+        let mut channels = self.channels.lock().expect("map channels lock poisoned");
+        channels.remove(&map_id);
+        let _ = self.closed.send(map_id);
+    }
+
+    /// Pass a notification on to the subscribers of its map, if it belongs to one
+    pub(super) fn publish(&self, notification: &SystemNotification) {
+        let Some(map_id) = notification.map_id() else {
+            return;
+        };
+
+        let mut channels = self.channels.lock().expect("map channels lock poisoned");
+        let unused = channels
+            .get(&map_id)
+            .is_some_and(|sender| sender.send(notification.clone()).is_err());
+        if unused {
+            channels.remove(&map_id);
+        }
+    }
+}
@@ -0,0 +1,1195 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ::metrics::counter;
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use opentelemetry::KeyValue;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::MissedTickBehavior;
+use tokio_postgres::{AsyncMessage, Client, Connection, NoTls};
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::NotifyMode;
+use crate::db::tls::DbTls;
+use crate::metrics::instruments;
+use crate::models::{MapConnection, MapSignature, MapSystem};
+
+mod coalesce;
+mod fetch;
+mod maps;
+mod payload;
+mod replay;
+mod status;
+mod tables;
+
+use fetch::PendingChange;
+use maps::MapChannels;
+pub use payload::{NotificationPayload, Operation};
+use status::ListenerStats;
+pub use status::ListenerStatus;
+pub use tables::NotifyTables;
+
+pub use tokio_postgres::Notification;
+
+pub const SYSTEM_INSERT_CHANNEL: &str = "system_insert";
+pub const SYSTEM_UPDATE_CHANNEL: &str = "system_update";
+pub const SYSTEM_DELETE_CHANNEL: &str = "system_delete";
+pub const SIGNATURE_INSERT_CHANNEL: &str = "signature_insert";
+pub const SIGNATURE_UPDATE_CHANNEL: &str = "signature_update";
+pub const SIGNATURE_DELETE_CHANNEL: &str = "signature_delete";
+pub const CONNECTION_INSERT_CHANNEL: &str = "connection_insert";
+pub const CONNECTION_UPDATE_CHANNEL: &str = "connection_update";
+pub const CONNECTION_DELETE_CHANNEL: &str = "connection_delete";
+
+/// Every channel the installed triggers publish on
+pub const ALL_CHANNELS: &[&str] = &[
+    SYSTEM_INSERT_CHANNEL,
+    SYSTEM_UPDATE_CHANNEL,
+    SYSTEM_DELETE_CHANNEL,
+    SIGNATURE_INSERT_CHANNEL,
+    SIGNATURE_UPDATE_CHANNEL,
+    SIGNATURE_DELETE_CHANNEL,
+    CONNECTION_INSERT_CHANNEL,
+    CONNECTION_UPDATE_CHANNEL,
+    CONNECTION_DELETE_CHANNEL,
+];
+
+/// Trigger publishing map system changes on the `system_*` channels as a
+/// [`NotificationPayload`]. When the rows would not fit the 8000 byte NOTIFY limit only the
+/// keys are sent and the listener loads the row itself.
+///
+/// The placeholder comment is replaced by [`system_trigger_sql`] with the watched column
+/// check, and `{systems}` with the systems table.
+const SYSTEM_TRIGGER_SQL: &str = r#"
+CREATE OR REPLACE FUNCTION system_notify() RETURNS trigger AS $$
+DECLARE
+    envelope jsonb;
+    payload text;
+BEGIN
+    -- watched columns
+    IF TG_OP = 'DELETE' THEN
+        envelope := jsonb_build_object('op', 'delete', 'id', OLD.id, 'map_id', OLD.map_id);
+    ELSE
+        envelope := jsonb_build_object('op', lower(TG_OP), 'id', NEW.id, 'map_id', NEW.map_id);
+    END IF;
+
+    payload := (envelope || jsonb_build_object(
+        'old', CASE WHEN TG_OP <> 'INSERT' THEN to_jsonb(OLD) END,
+        'new', CASE WHEN TG_OP <> 'DELETE' THEN to_jsonb(NEW) END
+    ))::text;
+    IF octet_length(payload) >= 8000 THEN
+        payload := envelope::text;
+    END IF;
+
+    PERFORM pg_notify('system_' || lower(TG_OP), payload);
+    -- Ignored for AFTER triggers
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS new_system_trigger ON {systems};
+DROP TRIGGER IF EXISTS updated_system_trigger ON {systems};
+DROP TRIGGER IF EXISTS deleted_system_trigger ON {systems};
+DROP FUNCTION IF EXISTS new_system_notify();
+DROP FUNCTION IF EXISTS updated_system_notify();
+DROP FUNCTION IF EXISTS deleted_system_notify();
+
+DROP TRIGGER IF EXISTS system_trigger ON {systems};
+CREATE TRIGGER system_trigger
+    AFTER INSERT OR UPDATE OR DELETE ON {systems}
+    FOR EACH ROW EXECUTE FUNCTION system_notify();
+"#;
+
+// Same-named tables in other schemas are not the ones the unqualified names resolve to
+const SYSTEM_COLUMNS_SQL: &str = "SELECT column_name::text FROM information_schema.columns \
+     WHERE table_schema = current_schema() AND table_name = $1";
+
+/// The system trigger, skipping updates that leave every `watched` column unchanged. With
+/// no watched columns every update is published.
+fn system_trigger_sql(tables: &NotifyTables, watched: &[String]) -> String {
+    if watched.is_empty() {
+        return tables.render(SYSTEM_TRIGGER_SQL);
+    }
+
+    let columns = |row: &str| {
+        watched
+            .iter()
+            .map(|column| format!("{}.{}", row, quote_ident(column)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let check = format!(
+        "    IF TG_OP = 'UPDATE' AND ROW({}) IS NOT DISTINCT FROM ROW({}) THEN\n        \
+         RETURN NULL;\n    END IF;\n",
+        columns("OLD"),
+        columns("NEW")
+    );
+
+    tables.render(&SYSTEM_TRIGGER_SQL.replace("    -- watched columns\n", &check))
+}
+
+/// Fail on watched columns the systems table does not have. The trigger would otherwise
+/// only notice on the first update, and fail that write.
+async fn check_watched_columns(
+    client: &Client,
+    tables: &NotifyTables,
+    watched: &[String],
+) -> Result<(), anyhow::Error> {
+    let columns: Vec<String> = client
+        .query(SYSTEM_COLUMNS_SQL, &[&tables.systems])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    match watched.iter().find(|column| !columns.contains(column)) {
+        Some(unknown) => Err(anyhow::anyhow!(
+            "{} has no column {:?} to watch for updates",
+            tables.systems,
+            unknown
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Triggers publishing signature changes on the `signature_*` channels, installed on
+/// `{signatures}`
+const SIGNATURE_TRIGGER_SQL: &str = r#"
+CREATE OR REPLACE FUNCTION new_signature_notify() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('signature_insert', json_build_object('op', 'insert', 'id', NEW.id)::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION updated_signature_notify() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('signature_update', json_build_object('op', 'update', 'id', NEW.id)::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION deleted_signature_notify() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify(
+        'signature_delete',
+        json_build_object('op', 'delete', 'id', OLD.id, 'system_id', OLD.system_id)::text
+    );
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS new_signature_trigger ON {signatures};
+CREATE TRIGGER new_signature_trigger
+    AFTER INSERT ON {signatures}
+    FOR EACH ROW EXECUTE FUNCTION new_signature_notify();
+
+DROP TRIGGER IF EXISTS updated_signature_trigger ON {signatures};
+CREATE TRIGGER updated_signature_trigger
+    AFTER UPDATE ON {signatures}
+    FOR EACH ROW EXECUTE FUNCTION updated_signature_notify();
+
+DROP TRIGGER IF EXISTS deleted_signature_trigger ON {signatures};
+CREATE TRIGGER deleted_signature_trigger
+    AFTER DELETE ON {signatures}
+    FOR EACH ROW EXECUTE FUNCTION deleted_signature_notify();
+"#;
+
+/// Triggers publishing connection changes on the `connection_*` channels. Deletes carry the
+/// whole old row so consumers know which systems were disconnected; connection rows have
+/// no free text and stay small. Installed on `{connections}`.
+const CONNECTION_TRIGGER_SQL: &str = r#"
+CREATE OR REPLACE FUNCTION new_connection_notify() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('connection_insert', json_build_object('op', 'insert', 'id', NEW.id)::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION updated_connection_notify() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('connection_update', json_build_object('op', 'update', 'id', NEW.id)::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION deleted_connection_notify() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('connection_delete', row_to_json(OLD)::text);
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS new_connection_trigger ON {connections};
+CREATE TRIGGER new_connection_trigger
+    AFTER INSERT ON {connections}
+    FOR EACH ROW EXECUTE FUNCTION new_connection_notify();
+
+DROP TRIGGER IF EXISTS updated_connection_trigger ON {connections};
+CREATE TRIGGER updated_connection_trigger
+    AFTER UPDATE ON {connections}
+    FOR EACH ROW EXECUTE FUNCTION updated_connection_notify();
+
+DROP TRIGGER IF EXISTS deleted_connection_trigger ON {connections};
+CREATE TRIGGER deleted_connection_trigger
+    AFTER DELETE ON {connections}
+    FOR EACH ROW EXECUTE FUNCTION deleted_connection_notify();
+"#;
+
+/// Errors produced while turning a raw notification into a typed one
+#[derive(Debug, thiserror::Error)]
+pub enum NotificationError {
+    #[error("unknown notification channel: {0}")]
+    UnknownChannel(String),
+    #[error("invalid payload on channel {channel}: {source}")]
+    InvalidPayload {
+        channel: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("invalid row in {table}: {source}")]
+    InvalidRow {
+        table: &'static str,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("failed to load the changed row: {0}")]
+    Fetch(#[from] tokio_postgres::Error),
+}
+
+/// A change to a row in the systems, signatures or connections table, `map_system_v1`,
+/// `map_system_signatures_v1` and `map_connection_v1` by default
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum SystemNotification {
+    Insert(MapSystem),
+    /// `old` is the row before the update, when the trigger could include it
+    Update {
+        old: Option<Box<MapSystem>>,
+        new: MapSystem,
+    },
+    Delete {
+        id: Uuid,
+        map_id: Uuid,
+    },
+    SignatureInsert(MapSignature),
+    SignatureUpdate(MapSignature),
+    SignatureDelete {
+        id: Uuid,
+        system_id: Uuid,
+    },
+    ConnectionInsert(MapConnection),
+    ConnectionUpdate(MapConnection),
+    ConnectionDelete(MapConnection),
+}
+
+impl SystemNotification {
+    /// Every name [`kind`](Self::kind) can return
+    pub const KINDS: &'static [&'static str] = &[
+        "insert",
+        "update",
+        "delete",
+        "signature_insert",
+        "signature_update",
+        "signature_delete",
+        "connection_insert",
+        "connection_update",
+        "connection_delete",
+    ];
+
+    /// Short name of the change, e.g. `insert` or `signature_delete`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SystemNotification::Insert(_) => "insert",
+            SystemNotification::Update { .. } => "update",
+            SystemNotification::Delete { .. } => "delete",
+            SystemNotification::SignatureInsert(_) => "signature_insert",
+            SystemNotification::SignatureUpdate(_) => "signature_update",
+            SystemNotification::SignatureDelete { .. } => "signature_delete",
+            SystemNotification::ConnectionInsert(_) => "connection_insert",
+            SystemNotification::ConnectionUpdate(_) => "connection_update",
+            SystemNotification::ConnectionDelete(_) => "connection_delete",
+        }
+    }
+
+    /// The map a change belongs to. Signature changes only carry their system id.
+    pub fn map_id(&self) -> Option<Uuid> {
+        match self {
+            SystemNotification::Insert(system) | SystemNotification::Update { new: system, .. } => {
+                Some(system.map_id)
+            }
+            SystemNotification::Delete { map_id, .. } => Some(*map_id),
+            SystemNotification::ConnectionInsert(connection)
+            | SystemNotification::ConnectionUpdate(connection)
+            | SystemNotification::ConnectionDelete(connection) => Some(connection.map_id),
+            _ => None,
+        }
+    }
+
+    /// Whether this is an update that only moved a system on its map, so consumers that
+    /// don't draw the map can skip it. Updates without their old row are never treated as
+    /// moves. To not publish moves at all, leave the position columns out of
+    /// [`watched_system_columns`](NotificationListenerBuilder::watched_system_columns).
+    pub fn is_position_only(&self) -> bool {
+        let SystemNotification::Update {
+            old: Some(old),
+            new,
+        } = self
+        else {
+            return false;
+        };
+        let moved = old.position_x != new.position_x || old.position_y != new.position_y;
+        let unmoved = MapSystem {
+            position_x: new.position_x,
+            position_y: new.position_y,
+            updated_at: new.updated_at,
+            ..(**old).clone()
+        };
+
+        moved && unmoved == *new
+    }
+
+    /// The system a system or signature change concerns. Connections join two systems and
+    /// have no single one.
+    pub fn system_id(&self) -> Option<Uuid> {
+        match self {
+            SystemNotification::Insert(system) | SystemNotification::Update { new: system, .. } => {
+                Some(system.id)
+            }
+            SystemNotification::Delete { id, .. } => Some(*id),
+            SystemNotification::SignatureInsert(signature)
+            | SystemNotification::SignatureUpdate(signature) => Some(signature.system_id),
+            SystemNotification::SignatureDelete { system_id, .. } => Some(*system_id),
+            _ => None,
+        }
+    }
+}
+
+/// The change types a stream client asked for, parsed from a comma-separated list of
+/// [`SystemNotification::kind`] names such as `insert,delete`. The default accepts all.
+#[derive(Debug, Clone, Default)]
+pub struct KindFilter(HashSet<&'static str>);
+
+impl KindFilter {
+    /// Kinds whose notifications carry no map id, see [`SystemNotification::map_id`]
+    const WITHOUT_MAP_ID: &'static [&'static str] =
+        &["signature_insert", "signature_update", "signature_delete"];
+
+    pub fn accepts(&self, notification: &SystemNotification) -> bool {
+        self.0.is_empty() || self.0.contains(notification.kind())
+    }
+
+    /// Refuse kinds that a stream picking notifications by map id would never deliver,
+    /// rather than leave the client waiting on a stream that stays silent
+    pub fn for_map_stream(self) -> Result<Self, String> {
+        match Self::WITHOUT_MAP_ID
+            .iter()
+            .find(|kind| self.0.contains(*kind))
+        {
+            Some(kind) => Err(format!(
+                "{} changes carry no map id, so they cannot be streamed by map",
+                kind
+            )),
+            None => Ok(self),
+        }
+    }
+}
+
+impl FromStr for KindFilter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|kind| !kind.is_empty())
+            .map(|kind| {
+                SystemNotification::KINDS
+                    .iter()
+                    .find(|known| **known == kind)
+                    .copied()
+                    .ok_or_else(|| {
+                        format!(
+                            "unknown change type {:?}, expected one of {}",
+                            kind,
+                            SystemNotification::KINDS.join(", ")
+                        )
+                    })
+            })
+            .collect::<Result<_, _>>()
+            .map(KindFilter)
+    }
+}
+
+/// Listens for Postgres notifications on a set of channels and fans the parsed changes out
+/// to any number of subscribers, reconnecting with backoff when the connection is lost.
+///
+/// Dropping the listener stops the background task and closes the connection. Call
+/// [`shutdown`](Self::shutdown) to close it cleanly instead.
+pub struct NotificationListener {
+    // Weak, so subscribers see the channel close once the coalescing task has flushed
+    sender: broadcast::WeakSender<SystemNotification>,
+    maps: MapChannels,
+    // Weak, so the coalescing task still ends once the sessions feeding it are gone
+    injected: mpsc::WeakUnboundedSender<SystemNotification>,
+    commands: mpsc::UnboundedSender<Command>,
+    stats: Arc<ListenerStats>,
+    unhealthy_after: Duration,
+    task: Mutex<Option<JoinHandle<()>>>,
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+/// Channel changes requested while the listener runs, applied by its background task
+#[derive(Debug)]
+enum Command {
+    Listen(String, oneshot::Sender<()>),
+    Unlisten(String, oneshot::Sender<()>),
+}
+
+impl NotificationListener {
+    /// Connect with the default backoff and capacity settings
+    pub async fn connect(database_url: &str, channels: &[&str]) -> Result<Self, anyhow::Error> {
+        Self::builder(database_url)
+            .channels(channels)
+            .connect()
+            .await
+    }
+
+    pub fn builder(database_url: &str) -> NotificationListenerBuilder {
+        NotificationListenerBuilder::new(database_url)
+    }
+
+    /// Receive every notification published from now on.
+    ///
+    /// Slow receivers never hold up the listener: once a receiver falls more than the channel
+    /// capacity behind, its next `recv()` returns `RecvError::Lagged` with the number of
+    /// skipped events and continues from the oldest event still buffered. Once the listener
+    /// has stopped, receivers get what is still buffered and then `RecvError::Closed`.
+    pub fn subscribe(&self) -> broadcast::Receiver<SystemNotification> {
+        match self.sender.upgrade() {
+            Some(sender) => sender.subscribe(),
+            // Already stopped, so hand out a receiver that is closed as well
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// Receive the notifications for a single map from now on, with the same lagging
+    /// behaviour as [`subscribe`](Self::subscribe).
+    ///
+    /// Only changes that carry a map id are routed here; signature changes identify just
+    /// their system and are only available through `subscribe`.
+    pub fn subscribe_map(&self, map_id: Uuid) -> broadcast::Receiver<SystemNotification> {
+        self.maps.subscribe(map_id)
+    }
+
+    /// Publish a notification that did not come from Postgres, e.g. to exercise stream
+    /// clients without writing to the database. It is coalesced and fanned out exactly as
+    /// one that did. Returns `false` once the listener has stopped.
+    pub fn inject(&self, notification: SystemNotification) -> bool {
+        self.injected
+            .upgrade()
+            .is_some_and(|sender| sender.send(notification).is_ok())
+    }
+
+    /// End every [`subscribe_map`](Self::subscribe_map) subscription to a map, e.g. once
+    /// the map is gone. Their receivers see `RecvError::Closed`, and the map's id is sent
+    /// to [`subscribe_closed`](Self::subscribe_closed) receivers.
+    pub fn close_map(&self, map_id: Uuid) {
+        self.maps.close(map_id);
+    }
+
+    /// Receive the id of every map closed with [`close_map`](Self::close_map) from now on,
+    /// for subscribers of [`subscribe`](Self::subscribe) that follow only some maps
+    pub fn subscribe_closed(&self) -> broadcast::Receiver<Uuid> {
+        self.maps.subscribe_closed()
+    }
+
+    /// Start listening on another channel. The channel is kept across reconnects.
+    ///
+    /// While the listener is reconnecting this waits until the connection is back.
+    pub async fn listen(&self, channel: &str) -> Result<(), anyhow::Error> {
+        self.command(|done| Command::Listen(channel.to_string(), done))
+            .await
+    }
+
+    /// Stop listening on a channel, including after future reconnects
+    pub async fn unlisten(&self, channel: &str) -> Result<(), anyhow::Error> {
+        self.command(|done| Command::Unlisten(channel.to_string(), done))
+            .await
+    }
+
+    async fn command(
+        &self,
+        command: impl FnOnce(oneshot::Sender<()>) -> Command,
+    ) -> Result<(), anyhow::Error> {
+        let (done, applied) = oneshot::channel();
+        self.commands
+            .send(command(done))
+            .map_err(|_| anyhow::anyhow!("notification listener is stopped"))?;
+        applied
+            .await
+            .map_err(|_| anyhow::anyhow!("notification listener is stopped"))
+    }
+
+    /// Whether the listener is connected, how often it reconnected and when it last heard
+    /// from Postgres
+    pub fn status(&self) -> ListenerStatus {
+        self.stats.snapshot()
+    }
+
+    /// Whether the listener is connected, or lost its connection less than
+    /// [`unhealthy_after`](NotificationListenerBuilder::unhealthy_after) ago
+    pub fn healthy(&self) -> bool {
+        self.status().disconnected_since.is_none_or(|since| {
+            (Utc::now() - since).to_std().unwrap_or_default() < self.unhealthy_after
+        })
+    }
+
+    /// Stop listening and drop the connection right away
+    pub fn stop(&self) {
+        if let Some(task) = self
+            .task
+            .lock()
+            .expect("listener task lock poisoned")
+            .as_ref()
+        {
+            task.abort();
+        }
+    }
+
+    /// Ask the background task to close the connection and wait up to `timeout` for it,
+    /// aborting it if it is stuck, e.g. on a connection that stopped responding. Returns
+    /// whether it stopped cleanly.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        if let Some(stop) = self
+            .stop
+            .lock()
+            .expect("listener stop lock poisoned")
+            .take()
+        {
+            let _ = stop.send(());
+        }
+        let Some(mut task) = self
+            .task
+            .lock()
+            .expect("listener task lock poisoned")
+            .take()
+        else {
+            return true;
+        };
+
+        match tokio::time::timeout(timeout, &mut task).await {
+            Ok(_) => {
+                info!("Notification listener shut down cleanly");
+                true
+            }
+            Err(_) => {
+                task.abort();
+                warn!(
+                    "Notification listener did not stop within {:?}, aborted it",
+                    timeout
+                );
+                false
+            }
+        }
+    }
+}
+
+impl Drop for NotificationListener {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Shared handle to the listener, cheap to clone into handlers
+pub type NotifierHandle = Arc<NotificationListener>;
+
+/// Configures how a [`NotificationListener`] connects and reconnects
+#[derive(Debug, Clone)]
+pub struct NotificationListenerBuilder {
+    database_url: String,
+    channels: Vec<String>,
+    capacity: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    backoff_multiplier: f64,
+    coalesce_window: Duration,
+    watched_system_columns: Vec<String>,
+    tables: NotifyTables,
+    mode: NotifyMode,
+    poll_interval: Duration,
+    unhealthy_after: Duration,
+    tls: Option<DbTls>,
+}
+
+impl NotificationListenerBuilder {
+    fn new(database_url: &str) -> Self {
+        Self {
+            database_url: database_url.to_string(),
+            channels: Vec::new(),
+            capacity: 1024,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            backoff_multiplier: 2.0,
+            coalesce_window: Duration::ZERO,
+            watched_system_columns: Vec::new(),
+            tables: NotifyTables::default(),
+            mode: NotifyMode::Listen,
+            poll_interval: Duration::from_secs(2),
+            unhealthy_after: Duration::from_secs(30),
+            tls: None,
+        }
+    }
+
+    /// Channels to LISTEN on, re-issued after every reconnect
+    pub fn channels(mut self, channels: &[&str]) -> Self {
+        self.channels = channels.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    /// Number of notifications buffered for subscribers before slow ones start lagging
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Delay before the first reconnect attempt
+    pub fn initial_backoff(mut self, delay: Duration) -> Self {
+        self.initial_backoff = delay;
+        self
+    }
+
+    /// Upper bound on the delay between reconnect attempts
+    pub fn max_backoff(mut self, delay: Duration) -> Self {
+        self.max_backoff = delay;
+        self
+    }
+
+    /// Factor applied to the delay after each failed attempt
+    pub fn backoff_multiplier(mut self, multiplier: f64) -> Self {
+        self.backoff_multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Collapse repeated updates to one system within this window into the latest one.
+    /// Inserts, deletes and other changes are never held back. Zero (the default) disables
+    /// coalescing.
+    pub fn coalesce_window(mut self, window: Duration) -> Self {
+        self.coalesce_window = window;
+        self
+    }
+
+    /// Only publish system updates that change one of these columns, so e.g. an
+    /// `updated_at`-only bump stays silent. Empty (the default) publishes every update.
+    ///
+    /// The columns are baked into the trigger when connecting, and the trigger is shared by
+    /// every listener on the database, so all of them should agree on the set.
+    pub fn watched_system_columns<S: AsRef<str>>(mut self, columns: &[S]) -> Self {
+        self.watched_system_columns = columns.iter().map(|c| c.as_ref().to_string()).collect();
+        self
+    }
+
+    /// The tables to install the triggers on and load rows from, the `_v1` tables by
+    /// default. Connecting fails if any of them does not exist.
+    pub fn tables(mut self, tables: NotifyTables) -> Self {
+        self.tables = tables;
+        self
+    }
+
+    /// LISTEN for notifications (the default), poll for changes, or LISTEN and poll if
+    /// that fails.
+    ///
+    /// Polling publishes the same notifications from the systems table's `updated_at`,
+    /// without installing the triggers, whose `pg_notify` would fail writes on a server
+    /// that disables it. It only sees system inserts and updates, whatever columns they
+    /// change: deletes and signature and connection changes go unnoticed.
+    pub fn mode(mut self, mode: NotifyMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// How often to look for changes when polling
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// How long the connection may be down before [`healthy`](NotificationListener::healthy)
+    /// reports false
+    pub fn unhealthy_after(mut self, delay: Duration) -> Self {
+        self.unhealthy_after = delay;
+        self
+    }
+
+    /// Connect over TLS instead of in plain text
+    pub fn tls(mut self, tls: Option<DbTls>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Connect, install the notify triggers and start listening, or start polling.
+    ///
+    /// The initial connection must succeed; later drops are retried in the background.
+    pub async fn connect(mut self) -> Result<NotificationListener, anyhow::Error> {
+        let (sender, _) = broadcast::channel(self.capacity);
+        let (incoming, received) = mpsc::unbounded_channel();
+        let (raw, raw_received) = mpsc::unbounded_channel();
+        let maps = MapChannels::new(self.capacity);
+        let stats = Arc::new(ListenerStats::default());
+
+        let session = Session::connect(&self, &raw, &stats).await?;
+        self.tables.check_exist(&session.client).await?;
+        // Settled here, so reconnects know whether to LISTEN
+        self.mode = match self.mode {
+            NotifyMode::Listen => {
+                session.listen(&self.channels).await?;
+                NotifyMode::Listen
+            }
+            NotifyMode::Poll => NotifyMode::Poll,
+            NotifyMode::Auto => match session.listen(&self.channels).await {
+                Ok(()) => NotifyMode::Listen,
+                Err(e) => {
+                    warn!("LISTEN is unavailable, polling for changes instead: {}", e);
+                    NotifyMode::Poll
+                }
+            },
+        };
+        if self.mode == NotifyMode::Listen {
+            check_watched_columns(&session.client, &self.tables, &self.watched_system_columns)
+                .await?;
+            install_triggers(&session.client, &self.tables, &self.watched_system_columns).await?;
+        } else {
+            info!(
+                "Polling {} for changes every {:?}",
+                self.tables.systems, self.poll_interval
+            );
+            stats.set_polling(true);
+        }
+        replay::init_high_water(&session.client, &self.tables, &stats).await?;
+
+        // Ends by itself once the sessions feeding it are gone
+        tokio::spawn(coalesce::run(
+            received,
+            sender.clone(),
+            maps.clone(),
+            self.coalesce_window,
+        ));
+        let injected = incoming.downgrade();
+        let sender = sender.downgrade();
+        let (commands, received_commands) = mpsc::unbounded_channel();
+        let (stop, stop_received) = oneshot::channel();
+        let unhealthy_after = self.unhealthy_after;
+        let task = tokio::spawn(supervise(
+            self,
+            raw,
+            raw_received,
+            incoming,
+            stats.clone(),
+            session,
+            received_commands,
+            stop_received,
+        ));
+
+        Ok(NotificationListener {
+            sender,
+            maps,
+            injected,
+            commands,
+            stats,
+            unhealthy_after,
+            task: Mutex::new(Some(task)),
+            stop: Mutex::new(Some(stop)),
+        })
+    }
+
+    fn polling(&self) -> bool {
+        self.mode == NotifyMode::Poll
+    }
+
+    fn next_backoff(&self, delay: Duration) -> Duration {
+        delay.mul_f64(self.backoff_multiplier).min(self.max_backoff)
+    }
+}
+
+/// Key of the advisory lock held while the notify triggers are installed
+const TRIGGER_SETUP_LOCK: i64 = 0x7761_6e64_6572;
+
+/// Install the notify triggers.
+///
+/// Every instance does this at startup, so the DDL runs in one transaction holding a
+/// transaction-level advisory lock: other instances wait for it instead of racing on the
+/// catalog, then replace the triggers with identical ones. The lock is released on commit,
+/// or when the connection drops. LISTEN needs no such care, each instance listens on its
+/// own connection.
+async fn install_triggers(
+    client: &Client,
+    tables: &NotifyTables,
+    watched_columns: &[String],
+) -> Result<(), tokio_postgres::Error> {
+    let sql = format!(
+        "BEGIN;\nSELECT pg_advisory_xact_lock({});\n{}\n{}\n{}\nCOMMIT;",
+        TRIGGER_SETUP_LOCK,
+        system_trigger_sql(tables, watched_columns),
+        tables.render(SIGNATURE_TRIGGER_SQL),
+        tables.render(CONNECTION_TRIGGER_SQL)
+    );
+
+    client.batch_execute(&sql).await
+}
+
+/// A single live connection with its LISTENs in place, or none when polling
+struct Session {
+    // Dropping the client closes the connection, so keep it alive alongside the driver
+    client: Client,
+    driver: JoinHandle<()>,
+    // The driver is a task of its own, so it must not outlive an aborted supervisor
+    _abort_driver: AbortOnDrop,
+}
+
+/// Aborts a task when dropped
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Session {
+    /// Connect and, unless polling, LISTEN on the configured channels
+    async fn open(
+        config: &NotificationListenerBuilder,
+        sender: &mpsc::UnboundedSender<Notification>,
+        stats: &Arc<ListenerStats>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        let session = Self::connect(config, sender, stats).await?;
+        if !config.polling() {
+            session.listen(&config.channels).await?;
+        }
+
+        Ok(session)
+    }
+
+    async fn connect(
+        config: &NotificationListenerBuilder,
+        sender: &mpsc::UnboundedSender<Notification>,
+        stats: &Arc<ListenerStats>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        let mut pg_config: tokio_postgres::Config = config.database_url.parse()?;
+
+        let (client, driver) = match &config.tls {
+            Some(tls) => {
+                pg_config.ssl_mode(tokio_postgres::config::SslMode::Require);
+                let (client, connection) = pg_config.connect(tls.connector()).await?;
+                (client, drive(connection, sender.clone(), stats.clone()))
+            }
+            None => {
+                let (client, connection) = pg_config.connect(NoTls).await?;
+                (client, drive(connection, sender.clone(), stats.clone()))
+            }
+        };
+
+        stats.set_connected(true);
+
+        Ok(Self {
+            client,
+            _abort_driver: AbortOnDrop(driver.abort_handle()),
+            driver,
+        })
+    }
+
+    async fn listen(&self, channels: &[String]) -> Result<(), tokio_postgres::Error> {
+        for channel in channels {
+            self.client
+                .batch_execute(&format!("LISTEN {}", quote_ident(channel)))
+                .await?;
+            info!("Listening for notifications on {}", channel);
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the connection to close
+    async fn closed(&mut self) {
+        let _ = (&mut self.driver).await;
+    }
+
+    /// Close the connection cleanly: without a client left the driver says goodbye to
+    /// Postgres and finishes
+    async fn close(self) {
+        let Session { client, driver, .. } = self;
+        drop(client);
+        let _ = driver.await;
+    }
+
+    /// Add or drop a channel in `channels` and issue the matching LISTEN/UNLISTEN, unless
+    /// polling.
+    ///
+    /// If the statement fails the connection is going away; the reconnect that follows
+    /// picks up the updated `channels`.
+    async fn apply(&self, command: Command, channels: &mut Vec<String>, polling: bool) {
+        let (statement, channel, done) = match command {
+            Command::Listen(channel, done) => {
+                if !channels.contains(&channel) {
+                    channels.push(channel.clone());
+                }
+                ("LISTEN", channel, done)
+            }
+            Command::Unlisten(channel, done) => {
+                channels.retain(|listened| *listened != channel);
+                ("UNLISTEN", channel, done)
+            }
+        };
+        if polling {
+            let _ = done.send(());
+            return;
+        }
+
+        match self
+            .client
+            .batch_execute(&format!("{} {}", statement, quote_ident(&channel)))
+            .await
+        {
+            Ok(()) => info!("{} {}", statement, channel),
+            Err(e) => warn!(
+                "{} {} failed, applying it on reconnect: {}",
+                statement, channel, e
+            ),
+        }
+
+        let _ = done.send(());
+    }
+}
+
+/// The connection has to be polled for the client to make progress, so drive it on its own
+/// task and hand notifications to the supervisor as they arrive
+fn drive<S, T>(
+    mut connection: Connection<S, T>,
+    sender: mpsc::UnboundedSender<Notification>,
+    stats: Arc<ListenerStats>,
+) -> JoinHandle<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    stats.record_notification();
+                    instruments().notifications_received.add(
+                        1,
+                        &[KeyValue::new("channel", notification.channel().to_string())],
+                    );
+                    // Only fails while the listener is being torn down
+                    let _ = sender.send(notification);
+                }
+                Ok(AsyncMessage::Notice(notice)) => {
+                    debug!("Postgres notice: {}", notice);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Notification connection error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        stats.set_connected(false);
+        info!("Notification connection closed");
+    })
+}
+
+/// Parse a raw notification, load the row it points at and pass it on towards the
+/// subscribers
+async fn publish(
+    client: &Client,
+    tables: &NotifyTables,
+    sender: &mpsc::UnboundedSender<SystemNotification>,
+    stats: &ListenerStats,
+    recent: &mut replay::RecentChanges,
+    notification: &Notification,
+) {
+    let change = match PendingChange::parse(notification.channel(), notification.payload()) {
+        Ok(change) => change,
+        // Lose the one event rather than the connection, e.g. after a table changes shape
+        Err(e) => {
+            warn!(
+                "Skipping malformed notification on {}: {}; payload: {}",
+                notification.channel(),
+                e,
+                notification.payload()
+            );
+            let channel = notification.channel().to_string();
+            counter!("notifications_malformed_total", "channel" => channel).increment(1);
+            instruments()
+                .notifications_dropped
+                .add(1, &[KeyValue::new("reason", "malformed")]);
+            return;
+        }
+    };
+
+    match change.resolve(client, tables).await {
+        Ok(Some(notification)) => {
+            debug!("Received {} notification", notification.kind());
+            if let SystemNotification::Insert(system)
+            | SystemNotification::Update { new: system, .. } = &notification
+            {
+                stats.observe_system_change(system.updated_at);
+                recent.record(system, stats);
+            }
+            // Only fails while the listener is being torn down
+            let _ = sender.send(notification);
+        }
+        Ok(None) => {
+            debug!(
+                "Row for notification on {} is already deleted",
+                notification.channel()
+            );
+            instruments()
+                .notifications_dropped
+                .add(1, &[KeyValue::new("reason", "row_deleted")]);
+        }
+        // System changes are picked up again by the replay after the next reconnect
+        Err(e) => {
+            warn!("Skipping notification on {}: {}", notification.channel(), e);
+            instruments()
+                .notifications_dropped
+                .add(1, &[KeyValue::new("reason", "fetch_failed")]);
+        }
+    }
+}
+
+/// Keep a session alive until the listener is stopped, applying channel changes to it and
+/// publishing its notifications in the order they arrived
+#[allow(clippy::too_many_arguments)]
+async fn supervise(
+    mut config: NotificationListenerBuilder,
+    raw: mpsc::UnboundedSender<Notification>,
+    mut raw_received: mpsc::UnboundedReceiver<Notification>,
+    sender: mpsc::UnboundedSender<SystemNotification>,
+    stats: Arc<ListenerStats>,
+    mut session: Session,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let mut poll = tokio::time::interval(config.poll_interval);
+    poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut recent = replay::RecentChanges::default();
+    let mut known = replay::KnownSystems::default();
+
+    loop {
+        tokio::select! {
+            _ = &mut stop => {
+                info!("Closing the notification listener connection");
+                session.close().await;
+                return;
+            }
+            _ = session.closed() => {
+                session = reconnect(&config, &raw, &sender, &stats, &mut recent).await;
+            }
+            Some(notification) = raw_received.recv() => {
+                publish(&session.client, &config.tables, &sender, &stats, &mut recent, &notification)
+                    .await;
+            }
+            Some(command) = commands.recv() => {
+                let polling = config.polling();
+                session.apply(command, &mut config.channels, polling).await;
+            }
+            _ = poll.tick(), if config.polling() => {
+                poll_changes(&session, &config, &sender, &stats, &mut recent, &mut known).await;
+            }
+        }
+    }
+}
+
+/// Publish the system changes and deletes since the last poll
+async fn poll_changes(
+    session: &Session,
+    config: &NotificationListenerBuilder,
+    sender: &mpsc::UnboundedSender<SystemNotification>,
+    stats: &ListenerStats,
+    recent: &mut replay::RecentChanges,
+    known: &mut replay::KnownSystems,
+) {
+    match replay::poll(
+        &session.client,
+        &config.tables,
+        sender,
+        stats,
+        recent,
+        known,
+    )
+    .await
+    {
+        Ok(0) => {}
+        Ok(polled) => {
+            debug!("Polled {} system changes", polled);
+            stats.record_notification();
+        }
+        // A broken connection is noticed and replaced by the supervisor
+        Err(e) => warn!("Failed to poll for system changes: {}", e),
+    }
+}
+
+/// Retry opening a session with exponential backoff until it succeeds
+async fn reconnect(
+    config: &NotificationListenerBuilder,
+    raw: &mpsc::UnboundedSender<Notification>,
+    sender: &mpsc::UnboundedSender<SystemNotification>,
+    stats: &Arc<ListenerStats>,
+    recent: &mut replay::RecentChanges,
+) -> Session {
+    let mut delay = config.initial_backoff;
+    let mut attempt: u32 = 1;
+
+    loop {
+        warn!(
+            "Reconnecting notification listener in {:?} (attempt {})",
+            delay, attempt
+        );
+        tokio::time::sleep(delay).await;
+
+        match Session::open(config, raw, stats).await {
+            Ok(session) => {
+                stats.record_reconnect();
+                info!(
+                    "Notification listener reconnected after {} attempt(s)",
+                    attempt
+                );
+                match replay::replay(&session.client, &config.tables, sender, stats, recent).await {
+                    Ok(0) => {}
+                    Ok(replayed) => info!(
+                        "Replayed {} system changes missed while disconnected",
+                        replayed
+                    ),
+                    Err(e) => warn!("Failed to replay missed system changes: {}", e),
+                }
+                return session;
+            }
+            Err(e) => {
+                warn!(
+                    "Notification listener reconnect attempt {} failed: {}",
+                    attempt, e
+                );
+                delay = config.next_backoff(delay);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Quote a channel name as a Postgres identifier
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::models::MapSystem;
+
+/// The statement that changed a row, from the trigger's `TG_OP`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// The envelope the systems table trigger publishes.
+///
+/// `old` is set for updates and deletes and `new` for inserts and updates. Both are left
+/// out when they would push the payload past the 8000 byte NOTIFY limit, so the keys are
+/// always there to load the row by.
+#[derive(Deserialize, Debug, Clone)]
+pub struct NotificationPayload {
+    pub op: Operation,
+    pub id: Uuid,
+    pub map_id: Uuid,
+    #[serde(default)]
+    pub old: Option<MapSystem>,
+    #[serde(default)]
+    pub new: Option<MapSystem>,
+}
@@ -0,0 +1,202 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use chrono::{NaiveDateTime, TimeDelta};
+use tokio::sync::mpsc;
+use tokio_postgres::Client;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::models::MapSystem;
+
+use super::status::ListenerStats;
+use super::tables::NotifyTables;
+use super::SystemNotification;
+
+/// How Postgres renders a `timestamp` as text
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+const HIGH_WATER_SQL: &str = "SELECT max(updated_at)::text FROM {systems}";
+
+/// How far before the high-water mark a replay reads. `updated_at` is when the writing
+/// transaction started, so a change committed after the mark can carry an older time;
+/// those of transactions shorter than this are still found.
+const REPLAY_OVERLAP: TimeDelta = TimeDelta::seconds(60);
+
+// The mark is passed as text so no chrono support is needed in tokio-postgres; with no
+// mark every row is replayed
+const REPLAY_SQL: &str = "SELECT row_to_json(s)::text FROM {systems} s \
+     WHERE $1::text IS NULL OR s.updated_at > $1::text::timestamp \
+     ORDER BY s.updated_at";
+
+const IDS_SQL: &str = "SELECT id::text, map_id::text FROM {systems}";
+
+/// Start the high-water mark at the newest system, so a reconnect only replays what
+/// changed after startup
+pub(super) async fn init_high_water(
+    client: &Client,
+    tables: &NotifyTables,
+    stats: &ListenerStats,
+) -> Result<(), tokio_postgres::Error> {
+    let row = client
+        .query_one(&tables.render(HIGH_WATER_SQL), &[])
+        .await?;
+    let newest = row
+        .get::<_, Option<String>>(0)
+        .and_then(|at| NaiveDateTime::parse_from_str(&at, TIMESTAMP_FORMAT).ok());
+    if let Some(newest) = newest {
+        stats.observe_system_change(newest);
+    }
+
+    Ok(())
+}
+
+/// The system changes already published that a replay may read again, by `updated_at`
+/// and id, so that none is sent twice. Only those within [`REPLAY_OVERLAP`] of the
+/// high-water mark are kept.
+#[derive(Default)]
+pub(super) struct RecentChanges(BTreeSet<(NaiveDateTime, Uuid)>);
+
+impl RecentChanges {
+    /// Remember a published change, forgetting those no replay reads again
+    pub(super) fn record(&mut self, system: &MapSystem, stats: &ListenerStats) {
+        self.0.insert((system.updated_at, system.id));
+        if let Some(since) = replay_since(stats) {
+            self.0 = self.0.split_off(&(since, Uuid::nil()));
+        }
+    }
+
+    fn contains(&self, system: &MapSystem) -> bool {
+        self.0.contains(&(system.updated_at, system.id))
+    }
+}
+
+/// Where a replay starts reading, or `None` to read every system
+fn replay_since(stats: &ListenerStats) -> Option<NaiveDateTime> {
+    stats.system_high_water().map(|mark| mark - REPLAY_OVERLAP)
+}
+
+/// Publish every system changed since the high-water mark: as an insert when it was
+/// created after the mark, otherwise as an update without its old row. Returns how many
+/// were sent.
+///
+/// Covers the notifications lost while the listener was disconnected, running after the
+/// new session is listening so a change may arrive both ways but none is missed. The
+/// replay starts [`REPLAY_OVERLAP`] before the mark and skips the changes in `recent`.
+/// Deleted systems leave no row behind and cannot be replayed; when polling, [`poll`]
+/// finds them another way.
+pub(super) async fn replay(
+    client: &Client,
+    tables: &NotifyTables,
+    sender: &mpsc::UnboundedSender<SystemNotification>,
+    stats: &ListenerStats,
+    recent: &mut RecentChanges,
+) -> Result<usize, tokio_postgres::Error> {
+    replay_into(client, tables, sender, stats, recent, |_| {}).await
+}
+
+/// [`replay`], handing each replayed system to `seen` as well
+async fn replay_into(
+    client: &Client,
+    tables: &NotifyTables,
+    sender: &mpsc::UnboundedSender<SystemNotification>,
+    stats: &ListenerStats,
+    recent: &mut RecentChanges,
+    mut seen: impl FnMut(&MapSystem),
+) -> Result<usize, tokio_postgres::Error> {
+    let mark = stats.system_high_water();
+    let since = replay_since(stats).map(|at| at.format(TIMESTAMP_FORMAT).to_string());
+    let rows = client.query(&tables.render(REPLAY_SQL), &[&since]).await?;
+
+    let mut replayed = 0;
+    for row in rows {
+        let payload: String = row.get(0);
+        match serde_json::from_str::<MapSystem>(&payload) {
+            Ok(system) if recent.contains(&system) => seen(&system),
+            Ok(system) => {
+                stats.observe_system_change(system.updated_at);
+                recent.record(&system, stats);
+                seen(&system);
+                // Without a mark the table was empty at startup, so every row is new
+                let notification = if mark.is_none_or(|mark| system.inserted_at > mark) {
+                    SystemNotification::Insert(system)
+                } else {
+                    SystemNotification::Update {
+                        old: None,
+                        new: system,
+                    }
+                };
+                let _ = sender.send(notification);
+                replayed += 1;
+            }
+            Err(e) => warn!("Skipping unreadable system while replaying: {}", e),
+        }
+    }
+
+    Ok(replayed)
+}
+
+/// The systems known to exist while polling, by id, with the map each is on
+#[derive(Default)]
+pub(super) struct KnownSystems(Option<HashMap<Uuid, Uuid>>);
+
+/// Publish every system changed since the last poll as [`replay`] does, then every system
+/// deleted since as a delete. Returns how many were sent.
+///
+/// Deletes are found by loading every system id and comparing them with those known from
+/// earlier polls, so a delete is seen even when an insert keeps the number of systems the
+/// same. The first poll just learns which systems exist.
+pub(super) async fn poll(
+    client: &Client,
+    tables: &NotifyTables,
+    sender: &mpsc::UnboundedSender<SystemNotification>,
+    stats: &ListenerStats,
+    recent: &mut RecentChanges,
+    known: &mut KnownSystems,
+) -> Result<usize, tokio_postgres::Error> {
+    let Some(systems) = &mut known.0 else {
+        known.0 = Some(load_ids(client, tables).await?);
+        return replay(client, tables, sender, stats, recent).await;
+    };
+
+    let mut polled = replay_into(client, tables, sender, stats, recent, |system| {
+        systems.insert(system.id, system.map_id);
+    })
+    .await?;
+
+    let existing = load_ids(client, tables).await?;
+    let deleted: HashSet<Uuid> = systems
+        .keys()
+        .filter(|id| !existing.contains_key(id))
+        .copied()
+        .collect();
+    if !deleted.is_empty() {
+        debug!("{} systems were deleted since the last poll", deleted.len());
+    }
+    for id in deleted {
+        if let Some(map_id) = systems.remove(&id) {
+            let _ = sender.send(SystemNotification::Delete { id, map_id });
+            polled += 1;
+        }
+    }
+    // Also picks up systems inserted after the replay, whose changes the next poll replays
+    *systems = existing;
+
+    Ok(polled)
+}
+
+/// Every system id in the table with its map id
+async fn load_ids(
+    client: &Client,
+    tables: &NotifyTables,
+) -> Result<HashMap<Uuid, Uuid>, tokio_postgres::Error> {
+    let rows = client.query(&tables.render(IDS_SQL), &[]).await?;
+
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            let id = row.get::<_, String>(0).parse().ok()?;
+            let map_id = row.get::<_, String>(1).parse().ok()?;
+            Some((id, map_id))
+        })
+        .collect())
+}
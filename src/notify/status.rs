@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Point-in-time view of a listener's connection
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct ListenerStatus {
+    /// Whether a LISTEN connection is currently open
+    pub connected: bool,
+    /// Whether changes are polled for instead of listened to, with `NOTIFY_MODE=poll` or
+    /// after `auto` found LISTEN unavailable
+    pub polling: bool,
+    /// Successful reconnects since startup
+    pub reconnects: u64,
+    /// When the last notification arrived, if any has
+    pub last_notification_at: Option<DateTime<Utc>>,
+    /// When the connection was lost, while it is down
+    pub disconnected_since: Option<DateTime<Utc>>,
+}
+
+/// Counters shared between the listener's tasks, cheap enough to update per notification
+#[derive(Debug, Default)]
+pub(super) struct ListenerStats {
+    connected: AtomicBool,
+    polling: AtomicBool,
+    reconnects: AtomicU64,
+    /// Unix milliseconds, zero until the first notification
+    last_notification_ms: AtomicI64,
+    /// Unix milliseconds, zero while connected
+    disconnected_since_ms: AtomicI64,
+    /// `updated_at` of the newest system seen, in Unix microseconds; zero until known
+    system_high_water_us: AtomicI64,
+}
+
+impl ListenerStats {
+    pub(super) fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+        if connected {
+            self.disconnected_since_ms.store(0, Ordering::Relaxed);
+        } else {
+            // Keep the first drop when reconnect attempts fail in a row
+            let _ = self.disconnected_since_ms.compare_exchange(
+                0,
+                Utc::now().timestamp_millis(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    pub(super) fn set_polling(&self, polling: bool) {
+        self.polling.store(polling, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_notification(&self) {
+        self.last_notification_ms
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Move the replay high-water mark forward to `updated_at`, never back
+    pub(super) fn observe_system_change(&self, updated_at: NaiveDateTime) {
+        self.system_high_water_us
+            .fetch_max(updated_at.and_utc().timestamp_micros(), Ordering::Relaxed);
+    }
+
+    pub(super) fn system_high_water(&self) -> Option<NaiveDateTime> {
+        let micros = self.system_high_water_us.load(Ordering::Relaxed);
+        (micros > 0)
+            .then(|| DateTime::from_timestamp_micros(micros))
+            .flatten()
+            .map(|at| at.naive_utc())
+    }
+
+    pub(super) fn snapshot(&self) -> ListenerStatus {
+        let at = |millis: i64| {
+            (millis > 0)
+                .then(|| Utc.timestamp_millis_opt(millis).single())
+                .flatten()
+        };
+
+        ListenerStatus {
+            connected: self.connected.load(Ordering::Relaxed),
+            polling: self.polling.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            last_notification_at: at(self.last_notification_ms.load(Ordering::Relaxed)),
+            disconnected_since: at(self.disconnected_since_ms.load(Ordering::Relaxed)),
+        }
+    }
+}
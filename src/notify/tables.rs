@@ -0,0 +1,66 @@
+use tokio_postgres::Client;
+
+use super::quote_ident;
+
+// The table names are unqualified, so they are looked up in the current schema only
+const EXISTING_TABLES_SQL: &str = "SELECT table_name::text FROM information_schema.tables \
+     WHERE table_schema = current_schema() AND table_name = ANY($1)";
+
+/// The Wanderer tables the listener installs its triggers on and loads changed rows from.
+///
+/// Wanderer versions its tables with a suffix such as `_v1`; moving to a new version is a
+/// matter of passing [`NotifyTables::version`] to
+/// [`tables`](super::NotificationListenerBuilder::tables). Trigger names are scoped to
+/// their table in Postgres, so they stay the same across versions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyTables {
+    pub systems: String,
+    pub signatures: String,
+    pub connections: String,
+}
+
+impl NotifyTables {
+    /// The tables of Wanderer schema version `version`, e.g. `map_system_v2` for 2
+    pub fn version(version: u32) -> Self {
+        Self {
+            systems: format!("map_system_v{}", version),
+            signatures: format!("map_system_signatures_v{}", version),
+            connections: format!("map_connection_v{}", version),
+        }
+    }
+
+    /// Fill the `{systems}`, `{signatures}` and `{connections}` placeholders of `sql` with
+    /// the quoted table names
+    pub(super) fn render(&self, sql: &str) -> String {
+        sql.replace("{systems}", &quote_ident(&self.systems))
+            .replace("{signatures}", &quote_ident(&self.signatures))
+            .replace("{connections}", &quote_ident(&self.connections))
+    }
+
+    /// Fail on a table that does not exist, rather than on the trigger DDL with a less
+    /// helpful message
+    pub(super) async fn check_exist(&self, client: &Client) -> Result<(), anyhow::Error> {
+        let wanted = vec![&self.systems, &self.signatures, &self.connections];
+        let existing: Vec<String> = client
+            .query(EXISTING_TABLES_SQL, &[&wanted])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        match wanted.into_iter().find(|table| !existing.contains(table)) {
+            Some(missing) => Err(anyhow::anyhow!(
+                "table {} does not exist, so no notify triggers can be installed on it; \
+                 is the database at this Wanderer schema version?",
+                missing
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for NotifyTables {
+    fn default() -> Self {
+        Self::version(1)
+    }
+}
@@ -0,0 +1,97 @@
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::admin::{AdminStatus, PoolStatus};
+use crate::auth::API_KEY_HEADER;
+use crate::error::{ErrorBody, FieldError};
+use crate::models::{
+    AuditEntry, AuditEntryPage, MapConnection, MapSignature, MapSystem, MapSystemPage, Neighbor,
+    SyncSummary, SystemSnapshot,
+};
+use crate::notify::{ListenerStatus, SystemNotification};
+
+/// OpenAPI document served at `/openapi.json` and rendered at `/docs`
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "wanderer-connector", description = "Connector API for Wanderer DB"),
+    paths(
+        crate::routes::health,
+        crate::routes::ready,
+        crate::routes::get_map_systems,
+        crate::routes::count_map_systems,
+        crate::routes::delete_map_systems,
+        crate::routes::sync_map_systems,
+        crate::routes::get_system,
+        crate::routes::set_system_position,
+        crate::routes::get_system_by_solar_system_id,
+        crate::routes::get_system_signatures,
+        crate::routes::get_system_neighbors,
+        crate::routes::get_chain,
+        crate::sse::map_events,
+        crate::ws::ws_handler,
+        crate::admin::admin_status,
+        crate::admin::audit_log,
+        crate::routes::version,
+    ),
+    components(schemas(
+        crate::routes::HealthResponse,
+        crate::routes::ReadyResponse,
+        crate::routes::CountResponse,
+        crate::routes::DeletedResponse,
+        crate::routes::PositionUpdate,
+        crate::routes::VersionResponse,
+        ErrorBody,
+        FieldError,
+        MapSystem,
+        MapSystemPage,
+        SystemSnapshot,
+        SyncSummary,
+        MapSignature,
+        MapConnection,
+        Neighbor,
+        AuditEntry,
+        AuditEntryPage,
+        SystemNotification,
+        AdminStatus,
+        PoolStatus,
+        ListenerStatus,
+    )),
+    modifiers(&SecurityAddon),
+    tags(
+        (name = "health", description = "Liveness and readiness probes"),
+        (name = "systems", description = "Map systems and chains"),
+        (name = "events", description = "Live change streams"),
+        (name = "admin", description = "Operational status and auditing"),
+    )
+)]
+pub struct ApiDoc;
+
+/// The OpenAPI document, with the greeting endpoints when they are built
+pub fn api_doc() -> utoipa::openapi::OpenApi {
+    #[allow(unused_mut)]
+    let mut doc = ApiDoc::openapi();
+
+    #[cfg(feature = "demo-endpoints")]
+    doc.merge(crate::greeting::GreetingApiDoc::openapi());
+    #[cfg(feature = "inject-notifications")]
+    doc.merge(crate::inject::InjectApiDoc::openapi());
+
+    doc
+}
+
+/// Registers the two ways to pass an API key
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! OTLP over HTTP with protobuf bodies, for collectors that do not accept gRPC.
+//!
+//! `opentelemetry-otlp` only ships its HTTP exporter with a client of its own, so these
+//! post the same protobuf messages with the `reqwest` client the crate already uses.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use opentelemetry::global;
+use opentelemetry::metrics::{MetricsError, Result as MetricsResult};
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::metrics::data::{ResourceMetrics, Temporality};
+use opentelemetry_sdk::metrics::exporter::PushMetricsExporter;
+use opentelemetry_sdk::metrics::reader::{
+    AggregationSelector, DefaultAggregationSelector, DefaultTemporalitySelector,
+    TemporalitySelector,
+};
+use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind, MeterProvider, PeriodicReader};
+use opentelemetry_sdk::trace::{self, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use prost::Message;
+use reqwest::header::CONTENT_TYPE;
+
+/// The OTLP default for how long one export may take
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts encoded OTLP messages to one signal's path under the collector endpoint
+#[derive(Debug, Clone)]
+struct Endpoint {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Endpoint {
+    /// `endpoint` is the collector's base URL; `path` the signal's, e.g. `v1/traces`
+    fn new(endpoint: &str, path: &str) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder().timeout(EXPORT_TIMEOUT).build()?;
+        Ok(Self {
+            client,
+            url: format!("{}/{}", endpoint.trim_end_matches('/'), path),
+        })
+    }
+
+    fn send(&self, body: Vec<u8>) -> impl std::future::Future<Output = Result<(), String>> {
+        let request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .body(body);
+        let url = self.url.clone();
+
+        async move {
+            let response = request
+                .send()
+                .await
+                .map_err(|e| format!("OTLP export to {} failed: {}", url, e))?;
+            match response.status() {
+                status if status.is_success() => Ok(()),
+                status => Err(format!("OTLP export to {} returned {}", url, status)),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HttpSpanExporter(Endpoint);
+
+impl SpanExporter for HttpSpanExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let request = ExportTraceServiceRequest {
+            resource_spans: batch.into_iter().map(Into::into).collect(),
+        };
+        let sent = self.0.send(request.encode_to_vec());
+
+        Box::pin(async move { sent.await.map_err(TraceError::from) })
+    }
+}
+
+struct HttpMetricsExporter(Endpoint);
+
+impl TemporalitySelector for HttpMetricsExporter {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        DefaultTemporalitySelector::new().temporality(kind)
+    }
+}
+
+impl AggregationSelector for HttpMetricsExporter {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        DefaultAggregationSelector::new().aggregation(kind)
+    }
+}
+
+#[async_trait]
+impl PushMetricsExporter for HttpMetricsExporter {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> MetricsResult<()> {
+        let request = ExportMetricsServiceRequest::from(&*metrics);
+        self.0
+            .send(request.encode_to_vec())
+            .await
+            .map_err(MetricsError::Other)
+    }
+
+    async fn force_flush(&self) -> MetricsResult<()> {
+        // Nothing is buffered between exports
+        Ok(())
+    }
+
+    fn shutdown(&self) -> MetricsResult<()> {
+        Ok(())
+    }
+}
+
+/// Export spans in batches to `{endpoint}/v1/traces` and install the provider globally,
+/// as the gRPC pipeline's `install_batch` does
+pub fn install_tracer(endpoint: &str, config: trace::Config) -> Result<Tracer, TraceError> {
+    let exporter = Endpoint::new(endpoint, "v1/traces").map_err(|e| e.to_string())?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(HttpSpanExporter(exporter), runtime::Tokio)
+        .with_config(config)
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    global::set_tracer_provider(provider);
+
+    Ok(tracer)
+}
+
+/// Export metrics periodically to `{endpoint}/v1/metrics` and install the provider
+/// globally, as the gRPC pipeline's `build` does
+pub fn install_meter_provider(endpoint: &str, resource: Resource) -> MetricsResult<MeterProvider> {
+    let exporter =
+        Endpoint::new(endpoint, "v1/metrics").map_err(|e| MetricsError::Other(e.to_string()))?;
+    let reader = PeriodicReader::builder(HttpMetricsExporter(exporter), runtime::Tokio).build();
+    let provider = MeterProvider::builder()
+        .with_reader(reader)
+        .with_resource(resource)
+        .build();
+    global::set_meter_provider(provider.clone());
+
+    Ok(provider)
+}
@@ -0,0 +1,47 @@
+//! W3C trace context on HTTP headers, continuing traces started by callers and passing
+//! ours on to the services we call.
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::global;
+use opentelemetry::propagation::{Extractor, Injector};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Reads W3C trace context out of request headers
+pub(crate) struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Writes W3C trace context into request headers
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Headers carrying the current span's trace context, for outgoing requests. Empty when
+/// the span is not sampled or OpenTelemetry is not set up.
+pub(crate) fn trace_headers() -> HeaderMap {
+    let context = Span::current().context();
+    let mut headers = HeaderMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+
+    headers
+}
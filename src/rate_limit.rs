@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use tracing::warn;
+
+use crate::auth::ApiKey;
+use crate::config::parse_env;
+use crate::error::ApiError;
+
+const DEFAULT_RATE_PER_SECOND: f64 = 10.0;
+const DEFAULT_BURST: u32 = 20;
+
+/// Above this many tracked clients, buckets that have refilled completely are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket rate limiter keyed by API key, or client IP for unauthenticated requests.
+///
+/// Each key gets `burst` tokens refilled at `rate_per_second`. Separate limiters can be
+/// layered onto different route groups to give expensive routes their own budget.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rate_per_second: f64,
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate_per_second: f64, burst: u32) -> Self {
+        Self {
+            rate_per_second,
+            burst: f64::from(burst.max(1)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Read `RATE_LIMIT_PER_SECOND` and `RATE_LIMIT_BURST`
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let rate = parse_env("RATE_LIMIT_PER_SECOND")?.unwrap_or(DEFAULT_RATE_PER_SECOND);
+        if rate <= 0.0 {
+            anyhow::bail!("RATE_LIMIT_PER_SECOND must be positive, got {}", rate);
+        }
+        let burst = parse_env("RATE_LIMIT_BURST")?.unwrap_or(DEFAULT_BURST);
+
+        Ok(Self::new(rate, burst))
+    }
+
+    /// Take a token for `key`, or return how long until one becomes available
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.with_bucket(key, |bucket| {
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                Ok(())
+            } else {
+                Err(self.wait_for_token(bucket))
+            }
+        })
+    }
+
+    /// How long until `key` has a token again, without taking one; `None` if it has one
+    pub fn retry_after(&self, key: &str) -> Option<Duration> {
+        self.with_bucket(key, |bucket| {
+            (bucket.tokens < 1.0).then(|| self.wait_for_token(bucket))
+        })
+    }
+
+    /// Run `f` on the refilled bucket of `key`
+    fn with_bucket<T>(&self, key: &str, f: impl FnOnce(&mut Bucket) -> T) -> T {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_TRACKED_CLIENTS {
+            let full_after = Duration::from_secs_f64(self.burst / self.rate_per_second);
+            buckets.retain(|_, bucket| now.duration_since(bucket.last_refill) < full_after);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate_per_second).min(self.burst);
+        bucket.last_refill = now;
+
+        f(bucket)
+    }
+
+    fn wait_for_token(&self, bucket: &Bucket) -> Duration {
+        Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate_per_second)
+    }
+}
+
+/// Reject requests over the caller's budget with `429 Too Many Requests`
+pub async fn rate_limit(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let key = client_key(&request);
+
+    if let Err(retry_after) = limiter.check(&key) {
+        warn!("Rate limit exceeded for {}", key);
+        return Err(ApiError::RateLimited(retry_after));
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Charge failed authentications to the client's IP, and turn its requests away with
+/// `429` before they are authenticated once its budget is spent, so keys cannot be
+/// guessed at full speed. Requests that authenticate cost nothing here.
+///
+/// The failures have a bucket of their own, apart from the IP's budget for public routes,
+/// so probes never use up the failure budget and failures never block probes.
+pub async fn limit_auth_failures(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let key = format!("auth-failures:{}", ip_key(&request));
+
+    if let Some(retry_after) = limiter.retry_after(&key) {
+        warn!(
+            "Rate limit exceeded for {} after failed authentications",
+            key
+        );
+        return Err(ApiError::RateLimited(retry_after));
+    }
+
+    let response = next.run(request).await;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        let _ = limiter.check(&key);
+    }
+
+    Ok(response)
+}
+
+fn client_key(request: &Request) -> String {
+    // Never the secret itself, as the key ends up in logs
+    match request.extensions().get::<ApiKey>() {
+        Some(key) => format!("key:{}", key.fingerprint()),
+        None => ip_key(request),
+    }
+}
+
+fn ip_key(request: &Request) -> String {
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+        None => "unknown".to_string(),
+    }
+}
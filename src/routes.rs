@@ -0,0 +1,817 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Extension, MatchedPath, Path, Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, Uri},
+    middleware,
+    response::{IntoResponse, Json, Response},
+    routing::{get, patch, post},
+    Router,
+};
+use opentelemetry::global;
+use serde::{Deserialize, Serialize};
+use tower::ServiceBuilder;
+use tower_http::classify::ServerErrorsFailureClass;
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
+use tracing::{debug, error, field, info, info_span, instrument, warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+use uuid::Uuid;
+
+use crate::auth::{self, ApiKey, ApiKeys};
+use crate::cache::SystemCache;
+use crate::chain::Homes;
+use crate::db::{self, DbPool, ReadPool};
+use crate::error::ApiError;
+use crate::event_log::EventLog;
+use crate::extract::{self, ApiJson};
+use crate::handlers::{MapConnectionRepository, MapSignatureRepository, MapSystemRepository};
+use crate::models::{
+    is_valid_solar_system_id, MapSignature, MapSystem, MapVersion, Neighbor, Pagination,
+    SyncSummary, SystemSnapshot, SOLAR_SYSTEM_IDS,
+};
+use crate::notify::NotifierHandle;
+use crate::propagation::HeaderExtractor;
+use crate::rate_limit::{self, RateLimiter};
+use crate::state::AppState;
+use crate::{admin, chain, metrics, openapi, sse, ws};
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct HealthResponse {
+    status: String,
+    timestamp: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ReadyResponse {
+    status: String,
+    timestamp: u64,
+    listener_connected: bool,
+    /// Seconds since the last notification arrived; null until one has
+    seconds_since_last_event: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct VersionResponse {
+    /// Crate version from `Cargo.toml`
+    version: &'static str,
+    /// Commit the binary was built from, `unknown` when it could not be determined
+    git_sha: &'static str,
+    /// When that commit was made, RFC 3339 in UTC, or `SOURCE_DATE_EPOCH` when building
+    /// without `.git`; `unknown` when neither is available
+    build_timestamp: &'static str,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CountResponse {
+    count: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DeletedResponse {
+    /// Number of rows removed
+    deleted: usize,
+}
+
+/// New position of a system on its map
+#[derive(Deserialize, ToSchema, Debug)]
+pub(crate) struct PositionUpdate {
+    x: i64,
+    y: i64,
+}
+
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct SystemParams {
+    /// Only systems carrying this label, e.g. `staging`, compared case-insensitively
+    label: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ChainParams {
+    /// Comma-separated EVE solar system ids of the home systems
+    home: String,
+}
+
+fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Liveness check: the process is up and serving requests
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "health",
+    responses(
+        (status = 200, description = "Process is up", body = HealthResponse),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    )
+)]
+#[instrument]
+pub(crate) async fn health() -> Json<HealthResponse> {
+    info!("Health check requested");
+    Json(HealthResponse {
+        status: "healthy".to_string(),
+        timestamp: unix_timestamp(),
+    })
+}
+
+/// Which build is running. The git commit and its time come from the build script, or
+/// from `GIT_SHA` and `SOURCE_DATE_EPOCH` when building without `.git`.
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Build information", body = VersionResponse),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument]
+pub(crate) async fn version() -> Json<VersionResponse> {
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        build_timestamp: env!("BUILD_TIMESTAMP"),
+    })
+}
+
+/// Readiness check: only healthy while the database, and the read replica if there is
+/// one, is reachable and the notification listener has not been disconnected for longer
+/// than `READY_LISTENER_DOWN_SECS`
+#[utoipa::path(
+    get,
+    path = "/ready",
+    tag = "health",
+    responses(
+        (status = 200, description = "Database and listener up", body = ReadyResponse),
+        (status = 503, description = "Database unreachable or listener down", body = ReadyResponse),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    )
+)]
+#[instrument(skip(pool, read_pool, listener))]
+pub(crate) async fn ready(
+    State(pool): State<DbPool>,
+    State(read_pool): State<ReadPool>,
+    State(listener): State<NotifierHandle>,
+) -> (StatusCode, Json<ReadyResponse>) {
+    let mut database_up = match db::ping(&pool).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Readiness check failed: {:#}", e);
+            false
+        }
+    };
+    if read_pool.is_replica() {
+        if let Err(e) = db::ping(&read_pool).await {
+            warn!("Readiness check failed on the read replica: {:#}", e);
+            database_up = false;
+        }
+    }
+    let listener_up = listener.healthy();
+    if !listener_up {
+        warn!("Readiness check failed: notification listener disconnected");
+    }
+
+    let (code, status) = if database_up && listener_up {
+        (StatusCode::OK, "healthy")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    };
+    let listener_status = listener.status();
+
+    (
+        code,
+        Json(ReadyResponse {
+            status: status.to_string(),
+            timestamp: unix_timestamp(),
+            listener_connected: listener_status.connected,
+            seconds_since_last_event: listener_status
+                .last_notification_at
+                .map(|at| (chrono::Utc::now() - at).num_seconds().max(0) as u64),
+        }),
+    )
+}
+
+/// List a page of the systems on a map, optionally only those with a given label.
+///
+/// Label filtering goes past the cache and an empty filtered page is not a 404. Responses
+/// carry a weak `ETag`; sending it back in `If-None-Match` gets a `304` until a system on
+/// the map changes.
+#[utoipa::path(
+    get,
+    path = "/maps/{map_id}/systems",
+    tag = "systems",
+    params(("map_id" = Uuid, Path, description = "Map id"), SystemParams, Pagination),
+    responses(
+        (status = 200, description = "One page of systems", body = crate::models::MapSystemPage),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 404, description = "Map has no systems", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool, read_pool, cache))]
+pub(crate) async fn get_map_systems(
+    State(pool): State<DbPool>,
+    State(read_pool): State<ReadPool>,
+    State(cache): State<SystemCache>,
+    Path(map_id): Path<Uuid>,
+    Query(params): Query<SystemParams>,
+    Query(pagination): Query<Pagination>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    // The cache is filled from the primary, so a lagging replica is never cached past its
+    // notification. The tag comes from the version the served page was loaded at, which
+    // for a cached page may trail the database until its notification arrives.
+    let (version, systems) = match params.label {
+        Some(label) => {
+            // Read before the page, so a change in between only ever makes the tag look stale
+            let version = MapSystemRepository::get_map_version(&read_pool, map_id).await?;
+            let systems =
+                MapSystemRepository::get_systems_by_label(&read_pool, map_id, label, pagination)
+                    .await?;
+            (version, systems)
+        }
+        None => {
+            let (version, systems) = cache.systems_page(&pool, map_id, pagination).await?;
+            if systems.total == 0 {
+                return Err(ApiError::NotFound(format!(
+                    "no systems found for map {}",
+                    map_id
+                )));
+            }
+            (version, systems)
+        }
+    };
+
+    let etag = systems_etag(version);
+    if etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    Ok(([(header::ETAG, etag)], Json(systems)).into_response())
+}
+
+/// Weak ETag for a page of a map's systems: the map's system count and the last time one
+/// of them changed, in microseconds since the epoch. Each page and label filter is its own
+/// resource, so the query needs no part in it.
+fn systems_etag((count, updated_at): MapVersion) -> String {
+    let updated_at = updated_at.map_or(0, |at| at.and_utc().timestamp_micros());
+    format!("W/\"{}-{}\"", count, updated_at)
+}
+
+/// Whether `If-None-Match` names `etag`, compared weakly as GET requires
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// Count the systems on a map, optionally only those with a given label, without loading
+/// them. A map without systems counts zero.
+#[utoipa::path(
+    get,
+    path = "/maps/{map_id}/systems/count",
+    tag = "systems",
+    params(("map_id" = Uuid, Path, description = "Map id"), SystemParams),
+    responses(
+        (status = 200, description = "Number of matching systems", body = CountResponse),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(read_pool))]
+pub(crate) async fn count_map_systems(
+    State(read_pool): State<ReadPool>,
+    Path(map_id): Path<Uuid>,
+    Query(params): Query<SystemParams>,
+) -> Result<Json<CountResponse>, ApiError> {
+    let count = MapSystemRepository::count_systems(&read_pool, map_id, params.label).await?;
+
+    Ok(Json(CountResponse { count }))
+}
+
+/// Delete every system on a map, e.g. after the map was deleted upstream, along with their
+/// signatures and connections. Every deleted row is audited. Also drops the map's cached
+/// pages, ends its event streams and unsubscribes WebSocket clients from it.
+#[utoipa::path(
+    delete,
+    path = "/maps/{map_id}/systems",
+    tag = "systems",
+    params(("map_id" = Uuid, Path, description = "Map id")),
+    responses(
+        (status = 200, description = "Number of systems deleted", body = DeletedResponse),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool, cache, listener, log))]
+pub(crate) async fn delete_map_systems(
+    State(pool): State<DbPool>,
+    State(cache): State<SystemCache>,
+    State(listener): State<NotifierHandle>,
+    State(log): State<EventLog>,
+    key: Option<Extension<ApiKey>>,
+    Path(map_id): Path<Uuid>,
+) -> Result<Json<DeletedResponse>, ApiError> {
+    let actor = key.map(|Extension(key)| key.actor());
+    let deleted = MapSystemRepository::delete_by_map_id(&pool, map_id, actor).await?;
+    info!("Deleted {} systems of map {}", deleted, map_id);
+
+    cache.invalidate(map_id);
+    listener.close_map(map_id);
+    log.close_map(map_id);
+
+    Ok(Json(DeletedResponse { deleted }))
+}
+
+/// Reconcile a map to a snapshot of its systems, e.g. when first syncing from Wanderer:
+/// systems are matched on their solar system id, inserted or updated to match the
+/// snapshot, and those missing from it are deleted with their signatures and connections.
+/// All of it happens in one transaction, and live consumers see the usual notifications.
+///
+/// An empty snapshot is refused rather than taken to delete every system; clear a map
+/// with `DELETE /maps/{map_id}/systems` instead.
+#[utoipa::path(
+    post,
+    path = "/maps/{map_id}/systems/sync",
+    tag = "systems",
+    params(("map_id" = Uuid, Path, description = "Map id")),
+    request_body = Vec<SystemSnapshot>,
+    responses(
+        (status = 200, description = "What the sync changed", body = SyncSummary),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 422, description = "Invalid or empty body, or a solar system id that is out of range or listed twice", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool, cache, snapshot))]
+pub(crate) async fn sync_map_systems(
+    State(pool): State<DbPool>,
+    State(cache): State<SystemCache>,
+    key: Option<Extension<ApiKey>>,
+    Path(map_id): Path<Uuid>,
+    ApiJson(snapshot): ApiJson<Vec<SystemSnapshot>>,
+) -> Result<Json<SyncSummary>, ApiError> {
+    if snapshot.is_empty() {
+        return Err(ApiError::Validation(format!(
+            "the snapshot is empty; to remove every system use DELETE /maps/{}/systems",
+            map_id
+        )));
+    }
+    if let Some(bogus) = snapshot
+        .iter()
+        .find(|system| !is_valid_solar_system_id(system.solar_system_id))
+    {
+        return Err(ApiError::Validation(format!(
+            "{} is not an EVE solar system id, expected {} to {}",
+            bogus.solar_system_id,
+            SOLAR_SYSTEM_IDS.start,
+            SOLAR_SYSTEM_IDS.end - 1
+        )));
+    }
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = snapshot
+        .iter()
+        .find(|system| !seen.insert(system.solar_system_id))
+    {
+        return Err(ApiError::Validation(format!(
+            "solar system {} is listed more than once",
+            duplicate.solar_system_id
+        )));
+    }
+
+    let actor = key.map(|Extension(key)| key.actor());
+    let summary = MapSystemRepository::sync_map(&pool, map_id, snapshot, actor).await?;
+    info!(
+        "Synced map {}: {} inserted, {} updated, {} deleted",
+        map_id, summary.inserted, summary.updated, summary.deleted
+    );
+
+    cache.invalidate(map_id);
+
+    Ok(Json(summary))
+}
+
+/// List the systems one connection away from a system, with the mass and time status of
+/// each connection. An isolated system has none.
+#[utoipa::path(
+    get,
+    path = "/systems/{id}/neighbors",
+    tag = "systems",
+    params(("id" = Uuid, Path, description = "System id")),
+    responses(
+        (status = 200, description = "Connected systems, by name", body = Vec<Neighbor>),
+        (status = 404, description = "No such system", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool))]
+pub(crate) async fn get_system_neighbors(
+    State(pool): State<ReadPool>,
+    Path(system_id): Path<Uuid>,
+) -> Result<Json<Vec<Neighbor>>, ApiError> {
+    // Tell an unknown system apart from an isolated one
+    if MapSystemRepository::get_system_by_id(&pool, system_id)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::NotFound(format!(
+            "system {} not found",
+            system_id
+        )));
+    }
+
+    let neighbors = MapConnectionRepository::get_neighbors(&pool, system_id).await?;
+    Ok(Json(neighbors))
+}
+
+/// Get a single system by id
+#[utoipa::path(
+    get,
+    path = "/systems/{id}",
+    tag = "systems",
+    params(("id" = Uuid, Path, description = "System id")),
+    responses(
+        (status = 200, description = "The system", body = MapSystem),
+        (status = 404, description = "No such system", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool))]
+pub(crate) async fn get_system(
+    State(pool): State<ReadPool>,
+    Path(system_id): Path<Uuid>,
+) -> Result<Json<MapSystem>, ApiError> {
+    MapSystemRepository::get_system_by_id(&pool, system_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("system {} not found", system_id)))
+}
+
+/// Move a system on its map. Only the position and `updated_at` are written, so this is
+/// cheap enough to call on every drag; the resulting update notification reports itself
+/// as position-only. Every move is audited.
+#[utoipa::path(
+    patch,
+    path = "/systems/{id}/position",
+    tag = "systems",
+    params(("id" = Uuid, Path, description = "System id")),
+    request_body = PositionUpdate,
+    responses(
+        (status = 200, description = "The moved system", body = MapSystem),
+        (status = 404, description = "No such system", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 422, description = "Invalid body", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool, cache))]
+pub(crate) async fn set_system_position(
+    State(pool): State<DbPool>,
+    State(cache): State<SystemCache>,
+    key: Option<Extension<ApiKey>>,
+    Path(system_id): Path<Uuid>,
+    ApiJson(position): ApiJson<PositionUpdate>,
+) -> Result<Json<MapSystem>, ApiError> {
+    let actor = key.map(|Extension(key)| key.actor());
+    let system = MapSystemRepository::set_position(&pool, system_id, position.x, position.y, actor)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("system {} not found", system_id)))?;
+
+    cache.invalidate(system.map_id);
+
+    Ok(Json(system))
+}
+
+/// Get a system on a map by its EVE solar system id
+#[utoipa::path(
+    get,
+    path = "/maps/{map_id}/systems/by-eve/{solar_system_id}",
+    tag = "systems",
+    params(
+        ("map_id" = Uuid, Path, description = "Map id"),
+        ("solar_system_id" = i64, Path, description = "EVE solar system id"),
+    ),
+    responses(
+        (status = 200, description = "The system", body = MapSystem),
+        (status = 404, description = "System not on the map", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool))]
+pub(crate) async fn get_system_by_solar_system_id(
+    State(pool): State<ReadPool>,
+    Path((map_id, solar_system_id)): Path<(Uuid, i64)>,
+) -> Result<Json<MapSystem>, ApiError> {
+    MapSystemRepository::get_by_solar_system_id(&pool, map_id, solar_system_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError::NotFound(format!(
+                "system {} not found on map {}",
+                solar_system_id, map_id
+            ))
+        })
+}
+
+/// List the signatures scanned in a system
+#[utoipa::path(
+    get,
+    path = "/systems/{id}/signatures",
+    tag = "systems",
+    params(("id" = Uuid, Path, description = "System id")),
+    responses(
+        (status = 200, description = "Signatures ordered by EVE id", body = Vec<MapSignature>),
+        (status = 404, description = "No such system", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool))]
+pub(crate) async fn get_system_signatures(
+    State(pool): State<ReadPool>,
+    Path(system_id): Path<Uuid>,
+) -> Result<Json<Vec<MapSignature>>, ApiError> {
+    // Tell an unknown system apart from one with nothing scanned yet
+    if MapSystemRepository::get_system_by_id(&pool, system_id)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::NotFound(format!(
+            "system {} not found",
+            system_id
+        )));
+    }
+
+    let signatures = MapSignatureRepository::get_signatures_by_system_id(&pool, system_id).await?;
+    Ok(Json(signatures))
+}
+
+/// List the systems connected to any of the home systems, directly or through other systems
+#[utoipa::path(
+    get,
+    path = "/maps/{map_id}/chain",
+    tag = "systems",
+    params(("map_id" = Uuid, Path, description = "Map id"), ChainParams),
+    responses(
+        (status = 200, description = "Ids of the systems in chain, homes first", body = Vec<Uuid>),
+        (status = 400, description = "Invalid home system list", body = crate::error::ErrorBody),
+        (status = 404, description = "Home system not on the map", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool))]
+pub(crate) async fn get_chain(
+    State(pool): State<ReadPool>,
+    Path(map_id): Path<Uuid>,
+    Query(params): Query<ChainParams>,
+) -> Result<Json<Vec<Uuid>>, ApiError> {
+    let homes: Homes = params.home.parse().map_err(ApiError::BadRequest)?;
+    let home_ids = homes.resolve(&pool, map_id).await?;
+
+    let chain = chain::chain_from_home(&pool, map_id, &home_ids).await?;
+    Ok(Json(chain))
+}
+
+/// Answer a path no route serves with the usual error body
+async fn not_found(method: Method, uri: Uri) -> ApiError {
+    ApiError::NotFound(format!("no route for {} {}", method, uri.path()))
+}
+
+/// Answer a route called with a method it does not serve with the usual error body. The
+/// `Allow` header is still set by the router.
+async fn method_not_allowed(method: Method, uri: Uri) -> ApiError {
+    ApiError::MethodNotAllowed(format!("{} is not allowed on {}", method, uri.path()))
+}
+
+/// Header carrying the correlation id for a request
+static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Build the tracing span for a request, tagged with its correlation id and the route
+/// template it matched. The status and latency are recorded once the response is ready.
+fn request_span(request: &Request<Body>) -> Span {
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or_default();
+
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id = %request_id,
+        "http.route" = route,
+        "http.status_code" = field::Empty,
+        latency_ms = field::Empty,
+        "otel.kind" = "server",
+        "otel.status_code" = field::Empty,
+    );
+
+    // Attach to the caller's trace so parent-based sampling sees its decision
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+
+    span
+}
+
+/// Record the status and latency of every response on its request span
+fn record_response<B>(response: &Response<B>, latency: Duration, span: &Span) {
+    span.record("http.status_code", response.status().as_u16());
+    span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+    debug!("finished processing request");
+}
+
+/// Mark the request span as failed, for server errors and responses that never finished
+fn record_failure(failure: ServerErrorsFailureClass, latency: Duration, span: &Span) {
+    span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+    span.record("otel.status_code", "ERROR");
+    match failure {
+        ServerErrorsFailureClass::StatusCode(status) => {
+            span.record("http.status_code", status.as_u16());
+            error!(status = status.as_u16(), "request failed");
+        }
+        ServerErrorsFailureClass::Error(e) => error!(error = %e, "request failed"),
+    }
+}
+
+/// Build the CORS policy from `ALLOWED_ORIGINS`, a comma-separated list of origins or `*`.
+/// With the variable unset no cross-origin requests are allowed.
+pub fn cors_layer() -> Result<CorsLayer, anyhow::Error> {
+    let origins = std::env::var("ALLOWED_ORIGINS").unwrap_or_default();
+
+    let allow_origin = if origins.trim() == "*" {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                HeaderValue::from_str(origin).map_err(|e| {
+                    anyhow::anyhow!("invalid origin {:?} in ALLOWED_ORIGINS: {}", origin, e)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if origins.is_empty() {
+            warn!("ALLOWED_ORIGINS is empty, cross-origin browser requests will be blocked");
+        }
+        AllowOrigin::list(origins)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PATCH,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            HeaderName::from_static(auth::API_KEY_HEADER),
+        ])
+        .expose_headers([REQUEST_ID_HEADER.clone()]))
+}
+
+/// Create the Axum router with all routes. Request bodies over `max_body_bytes` are
+/// rejected with `413 Payload Too Large`.
+pub fn create_router(
+    state: AppState,
+    api_keys: ApiKeys,
+    rate_limiter: RateLimiter,
+    cors: CorsLayer,
+    max_body_bytes: usize,
+) -> Router {
+    // Probes must work without credentials, so they are limited by client IP
+    let public = Router::new()
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .merge(SwaggerUi::new("/docs").url("/openapi.json", openapi::api_doc()))
+        .route_layer(middleware::from_fn_with_state(
+            rate_limiter.clone(),
+            rate_limit::rate_limit,
+        ));
+
+    let protected = Router::new()
+        .route(
+            "/maps/:map_id/systems",
+            get(get_map_systems).delete(delete_map_systems),
+        )
+        .route("/maps/:map_id/systems/count", get(count_map_systems))
+        .route("/maps/:map_id/systems/sync", post(sync_map_systems))
+        .route(
+            "/maps/:map_id/systems/by-eve/:solar_system_id",
+            get(get_system_by_solar_system_id),
+        )
+        .route("/systems/:id", get(get_system))
+        .route("/systems/:id/position", patch(set_system_position))
+        .route("/systems/:id/signatures", get(get_system_signatures))
+        .route("/systems/:id/neighbors", get(get_system_neighbors))
+        .route("/maps/:map_id/chain", get(get_chain))
+        .route("/maps/:map_id/events", get(sse::map_events))
+        .route("/ws", get(ws::ws_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+        .route("/admin/status", get(admin::admin_status))
+        .route("/admin/audit", get(admin::audit_log))
+        .route("/version", get(version));
+
+    #[cfg(feature = "demo-endpoints")]
+    let protected = protected.merge(crate::greeting::routes());
+    #[cfg(feature = "inject-notifications")]
+    let protected = protected.merge(crate::inject::routes());
+
+    let protected = protected
+        // Runs after authentication so limits apply per API key
+        .route_layer(middleware::from_fn_with_state(
+            rate_limiter.clone(),
+            rate_limit::rate_limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            api_keys,
+            auth::require_api_key,
+        ))
+        // Requests failing authentication are limited by client IP instead
+        .route_layer(middleware::from_fn_with_state(
+            rate_limiter,
+            rate_limit::limit_auth_failures,
+        ));
+
+    public
+        .merge(protected)
+        // After every route is registered, as it only applies to those already there
+        .method_not_allowed_fallback(method_not_allowed)
+        .fallback(not_found)
+        .route_layer(middleware::from_fn(metrics::track_metrics))
+        .layer(
+            ServiceBuilder::new()
+                .layer(SetRequestIdLayer::new(
+                    REQUEST_ID_HEADER.clone(),
+                    MakeRequestUuid,
+                ))
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(request_span)
+                        .on_response(record_response)
+                        .on_failure(record_failure),
+                )
+                .layer(PropagateRequestIdLayer::new(REQUEST_ID_HEADER.clone()))
+                // Compressing an event stream would buffer it, so SSE is always skipped
+                .layer(
+                    CompressionLayer::new()
+                        .compress_when(DefaultPredicate::new().and(NotForContentType::SSE)),
+                )
+                // Inside compression, so it reads the plain-text bodies uncompressed
+                .layer(middleware::map_response(extract::render_plain_errors))
+                // Replaces axum's own 2MB extractor limit so there is only one to configure
+                .layer(DefaultBodyLimit::disable())
+                .layer(RequestBodyLimitLayer::new(max_body_bytes)),
+        )
+        // Outermost so preflight requests are answered before authentication
+        .layer(cors)
+        .with_state(state)
+}
@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{stream, Stream};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, instrument, warn};
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::chain::{ChainFilter, Homes};
+use crate::config::{LagPolicy, StreamKeepAlive};
+use crate::db::DbPool;
+use crate::error::ApiError;
+use crate::event_log::{EventLog, LogEntry, LoggedEvent};
+use crate::notify::{KindFilter, SystemNotification};
+
+/// Sent by reconnecting clients with the id of the last event they received
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub struct EventParams {
+    /// Comma-separated EVE solar system ids of home systems; when set only changes in
+    /// chain from any of them are streamed
+    home: Option<String>,
+    /// Comma-separated change types to stream, e.g. `insert,delete`; all when unset. The
+    /// signature types need `home`, as signature changes carry no map id.
+    types: Option<String>,
+}
+
+/// Decides which notifications a stream forwards
+enum EventFilter {
+    Map(Uuid),
+    Chain(ChainFilter),
+}
+
+impl EventFilter {
+    async fn accepts(&mut self, notification: &SystemNotification) -> bool {
+        match self {
+            EventFilter::Map(map_id) => notification.map_id() == Some(*map_id),
+            EventFilter::Chain(chain) => chain.accepts(notification).await,
+        }
+    }
+}
+
+/// Stream live changes for a single map as Server-Sent Events, optionally narrowed to the
+/// systems in chain from any of `?home=<solar_system_id>,...` and to the change types in
+/// `?types=`.
+///
+/// Every event carries an `id:`. A client reconnecting with `Last-Event-Id` is first sent
+/// the events it missed, or a `resync` event when they are no longer kept, and then the
+/// live stream continues.
+///
+/// The subscription lives inside the response stream, so it is dropped as soon as the
+/// client disconnects. A `:keepalive` comment is sent after `STREAM_KEEPALIVE_SECS` without
+/// events. With `SLOW_CLIENT_POLICY=disconnect` a client that falls behind
+/// gets a final `resync` event and the stream ends, so it can reload the map.
+#[utoipa::path(
+    get,
+    path = "/maps/{map_id}/events",
+    tag = "events",
+    params(
+        ("map_id" = Uuid, Path, description = "Map id"),
+        ("Last-Event-Id" = Option<u64>, Header, description = "Id of the last event received, to resume after it"),
+        EventParams
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Stream of change events; `event:` is the change type",
+            content_type = "text/event-stream",
+            body = String
+        ),
+        (status = 400, description = "Unknown change type, signature type without `home`, or invalid home system list", body = crate::error::ErrorBody),
+        (status = 404, description = "Home system not on the map", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(pool, log, headers))]
+pub async fn map_events(
+    State(pool): State<DbPool>,
+    Path(map_id): Path<Uuid>,
+    Query(params): Query<EventParams>,
+    headers: HeaderMap,
+    State(log): State<EventLog>,
+    State(lag_policy): State<LagPolicy>,
+    State(StreamKeepAlive(keep_alive)): State<StreamKeepAlive>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ApiError> {
+    let kinds: KindFilter = params
+        .types
+        .as_deref()
+        .unwrap_or_default()
+        .parse()
+        .map_err(ApiError::BadRequest)?;
+    let homes: Option<Homes> = params
+        .home
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(ApiError::BadRequest)?;
+    // Only the chain filter can place signature changes, by their system
+    let kinds = match homes {
+        Some(_) => kinds,
+        None => kinds.for_map_stream().map_err(ApiError::BadRequest)?,
+    };
+
+    // An unreadable id resumes from nothing we kept, so the client resyncs
+    let last_event_id = headers.get(LAST_EVENT_ID_HEADER).map(|value| {
+        value
+            .to_str()
+            .ok()
+            .and_then(|id| id.trim().parse().ok())
+            .unwrap_or(0)
+    });
+
+    // Subscribe before loading the chain so no change slips through in between. Signature
+    // changes carry no map id, so a chain needs the log of every map.
+    let subscription = match homes {
+        Some(_) => log.subscribe(last_event_id),
+        None => log.subscribe_map(map_id, last_event_id),
+    };
+    let mut filter = match homes {
+        Some(homes) => {
+            let home_ids = homes.resolve(&pool, map_id).await?;
+            EventFilter::Chain(ChainFilter::new(pool, map_id, home_ids).await?)
+        }
+        None => EventFilter::Map(map_id),
+    };
+
+    let mut pending = VecDeque::new();
+    if subscription.gap {
+        info!(
+            "SSE client for map {} resumed from {:?}, which is no longer kept; asking it to resync",
+            map_id, last_event_id
+        );
+        pending.push_back(resync_event(map_id, None));
+    }
+    for event in &subscription.missed {
+        if kinds.accepts(&event.notification) && filter.accepts(&event.notification).await {
+            pending.push_back(to_event(event));
+        }
+    }
+
+    info!(
+        "SSE client subscribed to map {}, replaying {} events",
+        map_id,
+        pending.len()
+    );
+
+    // The flag ends the stream after the resync event has been sent
+    let kinds = Arc::new(kinds);
+    let stream = stream::unfold(
+        (pending, subscription.live, filter, false),
+        move |(mut pending, mut live, mut filter, finished)| {
+            let kinds = kinds.clone();
+            async move {
+                if let Some(event) = pending.pop_front() {
+                    return Some((event, (pending, live, filter, finished)));
+                }
+                if finished {
+                    return None;
+                }
+
+                loop {
+                    match live.recv().await {
+                        Ok(LogEntry::Event(event)) => {
+                            if kinds.accepts(&event.notification)
+                                && filter.accepts(&event.notification).await
+                            {
+                                return Some((to_event(&event), (pending, live, filter, false)));
+                            }
+                        }
+                        Ok(LogEntry::MapClosed(closed)) if closed == map_id => return None,
+                        Ok(LogEntry::MapClosed(_)) => {}
+                        Ok(LogEntry::Gap) => {
+                            return Some((
+                                resync_event(map_id, None),
+                                (pending, live, filter, false),
+                            ))
+                        }
+                        Err(RecvError::Lagged(skipped)) => match lag_policy {
+                            LagPolicy::Skip => warn!(
+                                "SSE client for map {} lagged, skipped {} events",
+                                map_id, skipped
+                            ),
+                            LagPolicy::Disconnect => {
+                                warn!(
+                                "SSE client for map {} lagged by {} events, asking it to resync",
+                                map_id, skipped
+                            );
+                                return Some((
+                                    resync_event(map_id, Some(skipped)),
+                                    (pending, live, filter, true),
+                                ));
+                            }
+                        },
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        },
+    );
+
+    // A `:keepalive` comment whenever no event was sent for a while
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(keep_alive).text("keepalive")))
+}
+
+/// Tell a client that fell behind where to reload the map from. `skipped` is unknown
+/// when the missed events were never counted.
+fn resync_event(map_id: Uuid, skipped: Option<u64>) -> Result<Event, axum::Error> {
+    Event::default().event("resync").json_data(json!({
+        "skipped": skipped,
+        "resync": format!("/maps/{}/systems", map_id),
+    }))
+}
+
+/// Build an SSE frame with the log id as `id:`, the change type as `event:` and the
+/// system as `data:`; updates send `{ "old": ..., "new": ... }`
+fn to_event(logged: &LoggedEvent) -> Result<Event, axum::Error> {
+    let notification = &logged.notification;
+    let event = Event::default()
+        .id(logged.id.to_string())
+        .event(notification.kind());
+
+    match notification {
+        SystemNotification::Insert(system) => event.json_data(system),
+        SystemNotification::Update { old, new } => {
+            event.json_data(json!({ "old": old, "new": new }))
+        }
+        SystemNotification::Delete { id, map_id } => {
+            event.json_data(json!({ "id": id, "map_id": map_id }))
+        }
+        SystemNotification::SignatureInsert(signature)
+        | SystemNotification::SignatureUpdate(signature) => event.json_data(signature),
+        SystemNotification::SignatureDelete { id, system_id } => {
+            event.json_data(json!({ "id": id, "system_id": system_id }))
+        }
+        SystemNotification::ConnectionInsert(connection)
+        | SystemNotification::ConnectionUpdate(connection)
+        | SystemNotification::ConnectionDelete(connection) => event.json_data(connection),
+    }
+}
@@ -0,0 +1,23 @@
+use axum::extract::FromRef;
+use metrics_exporter_prometheus::PrometheusHandle;
+
+use crate::cache::SystemCache;
+use crate::config::{LagPolicy, StreamKeepAlive};
+use crate::db::{DbPool, ReadPool};
+use crate::event_log::EventLog;
+use crate::notify::NotifierHandle;
+
+/// Services shared by every handler. Each field can be extracted on its own with
+/// `State<T>`, so handlers only name what they use.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    /// The primary, for writes and reads that must see the latest data
+    pub pool: DbPool,
+    pub read_pool: ReadPool,
+    pub notifier: NotifierHandle,
+    pub system_cache: SystemCache,
+    pub event_log: EventLog,
+    pub lag_policy: LagPolicy,
+    pub stream_keep_alive: StreamKeepAlive,
+    pub metrics: PrometheusHandle,
+}
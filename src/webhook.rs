@@ -0,0 +1,299 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::config::parse_env;
+use crate::notify::SystemNotification;
+use crate::propagation::trace_headers;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+/// One notification per request unless batching is asked for
+const DEFAULT_BATCH_SIZE: usize = 1;
+const DEFAULT_BATCH_INTERVAL: Duration = Duration::from_millis(1_000);
+const DEFAULT_WORKERS: usize = 1;
+/// Notifications queued per worker before the dispatcher waits for it
+const WORKER_QUEUE_CAPACITY: usize = 256;
+
+/// Body POSTed for every change: the notification's `type`/`data` plus routing ids.
+/// Batches are sent as an array of these.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    map_id: Option<Uuid>,
+    system_id: Option<Uuid>,
+    #[serde(flatten)]
+    notification: &'a SystemNotification,
+}
+
+impl<'a> WebhookPayload<'a> {
+    fn new(notification: &'a SystemNotification) -> Self {
+        Self {
+            map_id: notification.map_id(),
+            system_id: notification.system_id(),
+            notification,
+        }
+    }
+}
+
+/// Why a delivery failed, and whether it is worth another attempt
+enum DeliveryError {
+    Retryable(String),
+    Permanent(String),
+}
+
+/// Forwards every notification to an external HTTP endpoint as JSON.
+///
+/// Deliveries are spread over a pool of workers, each delivering one at a time. A
+/// notification goes to the worker picked by hashing its [`ordering_key`], so the changes
+/// to one system are delivered in order while different systems are delivered
+/// concurrently. The queues and the broadcast channel buffer changes while a delivery is
+/// retried, and a failed delivery is logged and dropped so the listener is never held up.
+///
+/// With a batch size above one, each worker collects notifications until its batch is
+/// full or the batch interval has passed since its first event, and delivers them
+/// together as a JSON array.
+pub struct WebhookForwarder {
+    client: reqwest::Client,
+    url: String,
+    max_retries: u32,
+    batch_size: usize,
+    batch_interval: Duration,
+    workers: usize,
+}
+
+impl WebhookForwarder {
+    /// Configure from `WEBHOOK_URL`, `WEBHOOK_TIMEOUT_SECS`, `WEBHOOK_MAX_RETRIES`,
+    /// `WEBHOOK_BATCH_SIZE`, `WEBHOOK_BATCH_INTERVAL_MS` and `WEBHOOK_WORKERS`. Returns
+    /// `None` when no URL is set.
+    pub fn from_env() -> Result<Option<Self>, anyhow::Error> {
+        let Some(url) = std::env::var("WEBHOOK_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+        else {
+            return Ok(None);
+        };
+
+        let timeout = parse_env("WEBHOOK_TIMEOUT_SECS")?.unwrap_or(DEFAULT_TIMEOUT_SECS);
+        let max_retries = parse_env("WEBHOOK_MAX_RETRIES")?.unwrap_or(DEFAULT_MAX_RETRIES);
+        let batch_size = parse_env("WEBHOOK_BATCH_SIZE")?.unwrap_or(DEFAULT_BATCH_SIZE);
+        let batch_interval = parse_env("WEBHOOK_BATCH_INTERVAL_MS")?
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_BATCH_INTERVAL);
+        let workers = parse_env("WEBHOOK_WORKERS")?.unwrap_or(DEFAULT_WORKERS);
+
+        if batch_size == 0 {
+            anyhow::bail!("WEBHOOK_BATCH_SIZE must be at least 1");
+        }
+        if workers == 0 {
+            anyhow::bail!("WEBHOOK_WORKERS must be at least 1");
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()?;
+
+        Ok(Some(Self {
+            client,
+            url,
+            max_retries,
+            batch_size,
+            batch_interval,
+            workers,
+        }))
+    }
+
+    /// Forward notifications from `events` until the channel closes, then finish what the
+    /// workers have queued. Aborting the returned task aborts the workers too.
+    pub fn spawn(self, mut events: broadcast::Receiver<SystemNotification>) -> JoinHandle<()> {
+        if self.batching() {
+            info!(
+                "Forwarding notifications to webhook {} on {} workers in batches of up to {} every {:?}",
+                self.url, self.workers, self.batch_size, self.batch_interval
+            );
+        } else {
+            info!(
+                "Forwarding notifications to webhook {} on {} workers",
+                self.url, self.workers
+            );
+        }
+
+        let forwarder = Arc::new(self);
+        // Owned by the dispatcher, so the workers are aborted along with it
+        let mut workers = JoinSet::new();
+        let queues: Vec<_> = (0..forwarder.workers)
+            .map(|_| {
+                let (queue, received) = mpsc::channel(WORKER_QUEUE_CAPACITY);
+                workers.spawn(forwarder.clone().work(received));
+                queue
+            })
+            .collect();
+
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(notification) => {
+                        let worker = shard(&notification, queues.len());
+                        // Waiting on a busy worker backs up into the broadcast channel
+                        if queues[worker].send(notification).await.is_err() {
+                            warn!("Webhook worker {} is gone, stopping", worker);
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Webhook forwarder lagged, dropped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+
+            // Closing the queues lets every worker deliver its last batch and stop
+            drop(queues);
+            while workers.join_next().await.is_some() {}
+        })
+    }
+
+    /// Deliver the notifications queued for one worker, in order, until its queue closes
+    async fn work(self: Arc<Self>, mut queue: mpsc::Receiver<SystemNotification>) {
+        let mut batch = Vec::new();
+        // When the oldest event in the batch is due, so quiet periods still deliver
+        let mut flush_at = Instant::now();
+
+        loop {
+            tokio::select! {
+                notification = queue.recv() => match notification {
+                    Some(notification) => {
+                        if batch.is_empty() {
+                            flush_at = Instant::now() + self.batch_interval;
+                        }
+                        batch.push(notification);
+                        if batch.len() >= self.batch_size {
+                            self.flush(&mut batch).await;
+                        }
+                    }
+                    None => break,
+                },
+                _ = sleep_until(flush_at), if !batch.is_empty() => {
+                    self.flush(&mut batch).await;
+                }
+            }
+        }
+
+        self.flush(&mut batch).await;
+    }
+
+    fn batching(&self) -> bool {
+        self.batch_size > 1
+    }
+
+    /// Deliver and clear the pending notifications
+    async fn flush(&self, batch: &mut Vec<SystemNotification>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        let payloads: Vec<_> = batch.iter().map(WebhookPayload::new).collect();
+        if self.batching() {
+            let description = format!("batch of {} notifications", payloads.len());
+            self.deliver(&payloads, &description).await;
+        } else {
+            for payload in &payloads {
+                let description = format!("{} notification", payload.notification.kind());
+                self.deliver(payload, &description).await;
+            }
+        }
+
+        batch.clear();
+    }
+
+    /// Every attempt carries the trace context of this span, so the receiver's spans join
+    /// the same trace
+    #[instrument(name = "webhook.deliver", skip(self, body))]
+    async fn deliver<T: Serialize + ?Sized>(&self, body: &T, description: &str) {
+        let mut backoff = INITIAL_RETRY_BACKOFF;
+        let mut retries = 0;
+
+        loop {
+            match self.post(body).await {
+                Ok(()) => {
+                    debug!("Delivered {} to webhook", description);
+                    return;
+                }
+                Err(DeliveryError::Retryable(e)) if retries < self.max_retries => {
+                    retries += 1;
+                    warn!(
+                        "Webhook delivery failed, retry {}/{} in {:?}: {}",
+                        retries, self.max_retries, backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(DeliveryError::Retryable(e)) | Err(DeliveryError::Permanent(e)) => {
+                    warn!(
+                        "Dropping {} after failed webhook delivery: {}",
+                        description, e
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn post<T: Serialize + ?Sized>(&self, body: &T) -> Result<(), DeliveryError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .headers(trace_headers())
+            .json(body)
+            .send()
+            .await
+            // Timeouts and connection failures are worth another attempt
+            .map_err(|e| DeliveryError::Retryable(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() {
+            Err(DeliveryError::Retryable(format!(
+                "server responded {}",
+                status
+            )))
+        } else {
+            Err(DeliveryError::Permanent(format!(
+                "server responded {}",
+                status
+            )))
+        }
+    }
+}
+
+/// Changes with the same key are delivered in order: a system's changes and those of its
+/// signatures by the system, a connection's by the connection
+fn ordering_key(notification: &SystemNotification) -> Option<Uuid> {
+    match notification {
+        SystemNotification::ConnectionInsert(connection)
+        | SystemNotification::ConnectionUpdate(connection)
+        | SystemNotification::ConnectionDelete(connection) => Some(connection.id),
+        _ => notification.system_id(),
+    }
+}
+
+/// The worker out of `workers` that delivers `notification`
+fn shard(notification: &SystemNotification, workers: usize) -> usize {
+    let Some(key) = ordering_key(notification) else {
+        return 0;
+    };
+
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
@@ -0,0 +1,201 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::Response,
+};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+use tracing::{debug, info, instrument, warn};
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::config::{LagPolicy, StreamKeepAlive};
+use crate::error::ApiError;
+use crate::notify::{KindFilter, NotifierHandle, SystemNotification};
+
+/// Close code sent to a client that fell behind, in the range reserved for applications
+pub const RESYNC_CLOSE_CODE: u16 = 4000;
+
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub struct WsParams {
+    /// Comma-separated change types to forward, e.g. `insert,delete`; all when unset. The
+    /// signature types are refused, as signature changes carry no map id.
+    types: Option<String>,
+}
+
+/// Control messages a client can send after connecting
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe(Uuid),
+    Unsubscribe(Uuid),
+}
+
+/// Upgrade to a WebSocket that forwards notifications for the maps a client subscribes to.
+///
+/// Send `{"subscribe": "<map_id>"}` or `{"unsubscribe": "<map_id>"}` to choose maps, and
+/// pass `?types=insert,delete` to only receive some change types. When a subscribed map's
+/// systems are deleted, the client is sent `{"map_closed": "<map_id>"}` and unsubscribed
+/// from it. With
+/// `SLOW_CLIENT_POLICY=disconnect` a client that falls behind is closed with code 4000 and
+/// should reload its maps before reconnecting. A ping is sent after `STREAM_KEEPALIVE_SECS`
+/// without messages.
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "events",
+    params(WsParams),
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol"),
+        (status = 400, description = "Unknown or signature change type", body = crate::error::ErrorBody),
+        (status = 401, description = "Missing or invalid API key", body = crate::error::ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = crate::error::ErrorBody),
+    ),
+    security(("api_key" = []), ("bearer" = []))
+)]
+#[instrument(skip(ws, listener))]
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(listener): State<NotifierHandle>,
+    State(lag_policy): State<LagPolicy>,
+    State(keep_alive): State<StreamKeepAlive>,
+    Query(params): Query<WsParams>,
+) -> Result<Response, ApiError> {
+    let kinds: KindFilter = params
+        .types
+        .as_deref()
+        .unwrap_or_default()
+        .parse()
+        .and_then(KindFilter::for_map_stream)
+        .map_err(ApiError::BadRequest)?;
+
+    Ok(ws.on_upgrade(move |socket| {
+        let events = listener.subscribe();
+        let closed = listener.subscribe_closed();
+        handle_socket(socket, events, closed, kinds, lag_policy, keep_alive)
+    }))
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    mut events: broadcast::Receiver<SystemNotification>,
+    mut closed: broadcast::Receiver<Uuid>,
+    kinds: KindFilter,
+    lag_policy: LagPolicy,
+    StreamKeepAlive(keep_alive): StreamKeepAlive,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut maps: HashSet<Uuid> = HashSet::new();
+    // Restarted after every message sent, so pings only go out on a quiet socket
+    let mut ping = interval_at(Instant::now() + keep_alive, keep_alive);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    info!("WebSocket client connected");
+
+    loop {
+        tokio::select! {
+            message = receiver.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(ClientMessage::Subscribe(map_id)) => {
+                            maps.insert(map_id);
+                            json!({ "subscribed": map_id })
+                        }
+                        Ok(ClientMessage::Unsubscribe(map_id)) => {
+                            maps.remove(&map_id);
+                            json!({ "unsubscribed": map_id })
+                        }
+                        Err(e) => json!({ "error": format!("invalid message: {}", e) }),
+                    };
+
+                    if sender.send(Message::Text(reply.to_string())).await.is_err() {
+                        break;
+                    }
+                    ping.reset();
+                }
+                // Pings are answered by the underlying WebSocket implementation
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {}
+                Some(Ok(Message::Binary(_))) => {
+                    debug!("Ignoring binary WebSocket message");
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Err(e)) => {
+                    warn!("WebSocket error: {}", e);
+                    break;
+                }
+            },
+            event = events.recv() => match event {
+                Ok(notification) => {
+                    let wanted = kinds.accepts(&notification)
+                        && notification.map_id().is_some_and(|map_id| maps.contains(&map_id));
+                    if !wanted {
+                        continue;
+                    }
+
+                    let payload = match serde_json::to_string(&notification) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            warn!("Failed to serialize notification: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if sender.send(Message::Text(payload)).await.is_err() {
+                        break;
+                    }
+                    ping.reset();
+                }
+                Err(RecvError::Lagged(skipped)) => match lag_policy {
+                    LagPolicy::Skip => {
+                        warn!("WebSocket client lagged, skipped {} events", skipped);
+                    }
+                    LagPolicy::Disconnect => {
+                        warn!(
+                            "WebSocket client lagged by {} events, asking it to resync",
+                            skipped
+                        );
+                        let close = CloseFrame {
+                            code: RESYNC_CLOSE_CODE,
+                            reason: format!("fell behind by {} events, resync", skipped).into(),
+                        };
+                        let _ = sender.send(Message::Close(Some(close))).await;
+                        break;
+                    }
+                },
+                Err(RecvError::Closed) => break,
+            },
+            map = closed.recv() => match map {
+                Ok(map_id) => {
+                    if !maps.remove(&map_id) {
+                        continue;
+                    }
+
+                    let closing = json!({ "map_closed": map_id });
+                    if sender.send(Message::Text(closing.to_string())).await.is_err() {
+                        break;
+                    }
+                    ping.reset();
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("WebSocket client missed {} closed maps", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = ping.tick() => {
+                if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    info!("WebSocket client disconnected");
+}
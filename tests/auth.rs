@@ -0,0 +1,15 @@
+//! API key fingerprints. Runs without Docker.
+
+use wanderer_connector::auth::ApiKey;
+
+#[test]
+fn fingerprint_reveals_nothing_of_the_key() {
+    let key = ApiKey("abcd1234".to_string());
+
+    let fingerprint = key.fingerprint();
+    assert_eq!(fingerprint, ApiKey("abcd1234".to_string()).fingerprint());
+    assert_ne!(fingerprint, ApiKey("abcd1235".to_string()).fingerprint());
+    assert_eq!(fingerprint.len(), 16);
+    assert!(!fingerprint.contains("abcd") && !fingerprint.contains("1234"));
+    assert_eq!(key.actor(), format!("api-key:{}", fingerprint));
+}
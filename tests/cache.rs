@@ -0,0 +1,52 @@
+//! `SystemCache` kept fresh by the notify pipeline. Needs Docker, so run with
+//! `cargo test -- --ignored`.
+
+mod common;
+
+use std::time::Duration;
+
+use uuid::Uuid;
+use wanderer_connector::cache::SystemCache;
+use wanderer_connector::models::Pagination;
+use wanderer_connector::notify::{NotificationListener, ALL_CHANNELS};
+
+const INVALIDATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn cached_page_is_invalidated_by_system_insert() {
+    let db = common::start().await;
+    let listener = NotificationListener::connect(&db.database_url, ALL_CHANNELS)
+        .await
+        .expect("failed to start listener");
+    let cache = SystemCache::new();
+    let _task = cache.clone().spawn(listener.subscribe());
+    let mut events = listener.subscribe();
+
+    let map_id = Uuid::new_v4();
+    common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    events.recv().await.unwrap();
+
+    let (_, page) = cache
+        .systems_page(&db.pool, map_id, Pagination::default())
+        .await
+        .unwrap();
+    assert_eq!(page.total, 1);
+
+    common::insert_system(&db.pool, map_id, 31000002, "J100002").await;
+    // The cache task handles the insert in its own time, until then the page is stale
+    tokio::time::timeout(INVALIDATION_TIMEOUT, async {
+        loop {
+            let (_, page) = cache
+                .systems_page(&db.pool, map_id, Pagination::default())
+                .await
+                .unwrap();
+            if page.total == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("cached page was never invalidated");
+}
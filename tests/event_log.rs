@@ -0,0 +1,109 @@
+//! Resuming from the SSE event log with `Last-Event-Id`
+
+use uuid::Uuid;
+use wanderer_connector::event_log::{EventLog, LogEntry};
+use wanderer_connector::notify::SystemNotification;
+
+fn deleted(map_id: Uuid) -> SystemNotification {
+    SystemNotification::Delete {
+        id: Uuid::new_v4(),
+        map_id,
+    }
+}
+
+#[tokio::test]
+async fn resume_replays_missed_events_then_goes_live() {
+    let log = EventLog::new(8);
+    let map_id = Uuid::new_v4();
+    let mut first = log.subscribe(None);
+    log.publish(deleted(map_id));
+    log.publish(deleted(map_id));
+    log.publish(deleted(map_id));
+
+    let Ok(LogEntry::Event(seen)) = first.live.recv().await else {
+        panic!("expected the first event");
+    };
+    let mut resumed = log.subscribe(Some(seen.id));
+    log.publish(deleted(map_id));
+
+    assert!(!resumed.gap);
+    let missed: Vec<_> = resumed.missed.iter().map(|event| event.id).collect();
+    assert_eq!(missed, [seen.id + 1, seen.id + 2]);
+    let Ok(LogEntry::Event(live)) = resumed.live.recv().await else {
+        panic!("expected a live event");
+    };
+    assert_eq!(live.id, seen.id + 3);
+}
+
+#[tokio::test]
+async fn resume_past_the_buffer_asks_for_resync() {
+    let log = EventLog::new(2);
+    let map_id = Uuid::new_v4();
+    let mut live = log.subscribe(None).live;
+    log.publish(deleted(map_id));
+    let Ok(LogEntry::Event(first)) = live.recv().await else {
+        panic!("expected the first event");
+    };
+    log.publish(deleted(map_id));
+    log.publish(deleted(map_id));
+    log.publish(deleted(map_id));
+
+    let resumed = log.subscribe(Some(first.id));
+    assert!(resumed.gap);
+    assert!(resumed.missed.is_empty());
+
+    // Ids from before a restart are older than anything this log handed out
+    assert!(log.subscribe(Some(1)).gap);
+    // Ids it never handed out at all
+    assert!(log.subscribe(Some(u64::MAX - 1)).gap);
+    assert!(log.subscribe(Some(u64::MAX)).gap);
+}
+
+#[tokio::test]
+async fn map_subscribers_are_unaffected_by_other_maps() {
+    let log = EventLog::new(2);
+    let (quiet, busy) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut live = log.subscribe_map(quiet, None).live;
+    log.publish(deleted(quiet));
+    let Ok(LogEntry::Event(first)) = live.recv().await else {
+        panic!("expected the quiet map's event");
+    };
+    log.publish(deleted(quiet));
+    for _ in 0..8 {
+        log.publish(deleted(busy));
+    }
+
+    // The burst neither lagged the live receiver nor pushed the quiet map's events out
+    let Ok(LogEntry::Event(second)) = live.recv().await else {
+        panic!("expected the quiet map's second event");
+    };
+    assert_eq!(second.id, first.id + 1);
+    let resumed = log.subscribe_map(quiet, Some(first.id));
+    assert!(!resumed.gap);
+    let missed: Vec<_> = resumed.missed.iter().map(|event| event.id).collect();
+    assert_eq!(missed, [second.id]);
+
+    // The log of every map did lose them
+    assert!(log.subscribe(Some(first.id)).gap);
+}
+
+#[tokio::test]
+async fn closing_a_map_ends_its_streams_and_forgets_its_events() {
+    let log = EventLog::new(8);
+    let map_id = Uuid::new_v4();
+    let mut live = log.subscribe_map(map_id, None).live;
+    log.publish(deleted(map_id));
+    let Ok(LogEntry::Event(seen)) = live.recv().await else {
+        panic!("expected the map's event");
+    };
+    log.publish(deleted(map_id));
+
+    log.close_map(map_id);
+
+    assert!(matches!(live.recv().await, Ok(LogEntry::Event(_))));
+    assert!(matches!(
+        live.recv().await,
+        Ok(LogEntry::MapClosed(closed)) if closed == map_id
+    ));
+    assert!(log.subscribe_map(map_id, Some(seen.id)).gap);
+}
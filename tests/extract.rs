@@ -0,0 +1,106 @@
+//! `ApiJson` rejections and the JSON rendering of plain-text ones. Runs without Docker.
+
+use axum::body::{to_bytes, Body};
+use axum::extract::FromRequest;
+use axum::http::{header::CONTENT_TYPE, Request, StatusCode};
+use axum::response::Response;
+use axum::routing::post;
+use axum::{middleware, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use tower_http::limit::RequestBodyLimitLayer;
+use wanderer_connector::extract::{render_plain_errors, ApiJson};
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct Route {
+    name: String,
+    hops: Vec<Hop>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct Hop {
+    system_id: u32,
+}
+
+async fn extract(body: &str) -> Result<ApiJson<Route>, Response> {
+    let request = Request::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    ApiJson::<Route>::from_request(request, &()).await
+}
+
+async fn rejection(body: &str) -> (StatusCode, Value) {
+    json_body(extract(body).await.expect_err("body was accepted")).await
+}
+
+async fn json_body(response: Response) -> (StatusCode, Value) {
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+#[tokio::test]
+async fn valid_body_is_extracted() {
+    let ApiJson(route) = extract(r#"{"name":"home","hops":[{"system_id":31000001}]}"#)
+        .await
+        .expect("body was rejected");
+    assert_eq!(route.name, "home");
+}
+
+#[tokio::test]
+async fn missing_field_is_named() {
+    let (status, body) = rejection(r#"{"hops":[]}"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "invalid_body");
+    assert_eq!(body["fields"][0]["field"], "name");
+}
+
+#[tokio::test]
+async fn nested_type_error_has_its_path() {
+    let (status, body) = rejection(r#"{"name":"home","hops":[{"system_id":"J1"}]}"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["fields"][0]["field"], "hops[0].system_id");
+}
+
+#[tokio::test]
+async fn malformed_json_has_no_field() {
+    let (status, body) = rejection(r#"{"name":"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["fields"][0]["field"], json!(null));
+    assert!(body["fields"][0]["message"].is_string());
+}
+
+#[tokio::test]
+async fn missing_content_type_is_a_json_415() {
+    let request = Request::builder()
+        .body(Body::from(r#"{"name":"home","hops":[]}"#))
+        .unwrap();
+    let response = ApiJson::<Route>::from_request(request, &())
+        .await
+        .expect_err("body was accepted");
+
+    let (status, body) = json_body(response).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(body["code"], "unsupported_media_type");
+}
+
+#[tokio::test]
+async fn body_limit_is_a_json_413() {
+    let app = Router::new()
+        .route("/", post(|ApiJson(_): ApiJson<Route>| async {}))
+        .layer(RequestBodyLimitLayer::new(8))
+        .layer(middleware::map_response(render_plain_errors));
+    let request = Request::post("/")
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(r#"{"name":"home","hops":[]}"#))
+        .unwrap();
+
+    let (status, body) = json_body(app.oneshot(request).await.unwrap()).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["code"], "payload_too_large");
+    assert!(body["error"].is_string());
+}
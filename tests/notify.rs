@@ -0,0 +1,572 @@
+//! The notify triggers and `NotificationListener` end to end. Needs Docker, so run with
+//! `cargo test -- --ignored`.
+
+mod common;
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use diesel::prelude::*;
+use futures::{stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_postgres::{AsyncMessage, NoTls};
+use uuid::Uuid;
+use wanderer_connector::config::NotifyMode;
+use wanderer_connector::db;
+use wanderer_connector::handlers::MapSystemRepository;
+use wanderer_connector::notify::{
+    NotificationListener, NotificationPayload, NotifyTables, Operation, SystemNotification,
+    ALL_CHANNELS,
+};
+use wanderer_connector::schema::map_system_v1;
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn inserted_system_is_broadcast() {
+    let db = common::start().await;
+    let listener = NotificationListener::connect(&db.database_url, ALL_CHANNELS)
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+
+    let notification = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("no notification received")
+        .unwrap();
+
+    match notification {
+        SystemNotification::Insert(system) => {
+            assert_eq!(system.id, id);
+            assert_eq!(system.map_id, map_id);
+        }
+        other => panic!("expected an insert, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn connecting_systems_is_broadcast() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let source = common::insert_system(&db.pool, map_id, 31000001, "Source").await;
+    let target = common::insert_system(&db.pool, map_id, 31000002, "Target").await;
+
+    let listener = NotificationListener::connect(&db.database_url, ALL_CHANNELS)
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+
+    common::insert_connection(&db.pool, map_id, source, target).await;
+
+    let notification = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("no notification received")
+        .unwrap();
+
+    match notification {
+        SystemNotification::ConnectionInsert(connection) => {
+            assert_eq!(connection.source_system_id, source);
+            assert_eq!(connection.target_system_id, target);
+        }
+        other => panic!("expected a connection insert, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn oversized_system_is_written_and_broadcast() {
+    let db = common::start().await;
+    let listener = NotificationListener::connect(&db.database_url, ALL_CHANNELS)
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+
+    // Well past the 8000 byte NOTIFY payload limit
+    let description = "x".repeat(16_000);
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    common::set_description(&db.pool, id, &description).await;
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        let notification = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+            .await
+            .expect("no notification received")
+            .unwrap();
+        received.push(notification);
+    }
+
+    match &received[1] {
+        SystemNotification::Update { new: system, .. } => {
+            assert_eq!(system.id, id);
+            assert_eq!(system.description.as_deref(), Some(description.as_str()));
+        }
+        other => panic!("expected an update, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn update_carries_the_old_row() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+
+    let listener = NotificationListener::connect(&db.database_url, ALL_CHANNELS)
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+
+    common::set_description(&db.pool, id, "static to C5").await;
+
+    let notification = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("no notification received")
+        .unwrap();
+
+    match notification {
+        SystemNotification::Update { old, new } => {
+            let old = old.expect("old row should fit in the payload");
+            assert_eq!(old.description, None);
+            assert_eq!(new.description.as_deref(), Some("static to C5"));
+        }
+        other => panic!("expected an update, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn updates_to_unwatched_columns_are_not_broadcast() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+
+    let listener = NotificationListener::builder(&db.database_url)
+        .channels(ALL_CHANNELS)
+        .watched_system_columns(&["status", "locked"])
+        .connect()
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+
+    common::set_description(&db.pool, id, "not watched").await;
+    common::set_status(&db.pool, id, 2).await;
+
+    let notification = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("no notification received")
+        .unwrap();
+
+    match notification {
+        SystemNotification::Update { old, new } => {
+            assert_eq!(old.map(|old| old.status), Some(0));
+            assert_eq!(new.status, 2);
+        }
+        other => panic!("expected an update, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn unknown_watched_column_is_rejected() {
+    let db = common::start().await;
+
+    let result = NotificationListener::builder(&db.database_url)
+        .channels(ALL_CHANNELS)
+        .watched_system_columns(&["no_such_column"])
+        .connect()
+        .await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn watched_columns_of_other_schemas_are_rejected() {
+    let db = common::start().await;
+    // A same-named table elsewhere must not make the column look like it exists
+    db::run(&db.pool, |conn| {
+        diesel::sql_query("CREATE SCHEMA archive").execute(conn)?;
+        diesel::sql_query("CREATE TABLE archive.map_system_v1 (archived_at TIMESTAMP)")
+            .execute(conn)
+    })
+    .await
+    .unwrap();
+
+    let result = NotificationListener::builder(&db.database_url)
+        .channels(ALL_CHANNELS)
+        .watched_system_columns(&["archived_at"])
+        .connect()
+        .await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn missing_table_version_is_rejected() {
+    let db = common::start().await;
+
+    let result = NotificationListener::builder(&db.database_url)
+        .channels(ALL_CHANNELS)
+        .tables(NotifyTables::version(2))
+        .connect()
+        .await;
+
+    let error = result.err().expect("connected without the tables");
+    assert!(error.to_string().contains("map_system_v2"));
+}
+
+/// Guards the seam between the trigger and the models: the payload Postgres actually sends
+/// must parse, and every column in it must be a `MapSystem` field. Serde ignores unknown
+/// fields, so a column added to `map_system_v1` but not the model is caught by comparing
+/// the keys, less the ones `MapSystem` derives.
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn system_payload_matches_model() {
+    let db = common::start().await;
+    // Installs the triggers
+    let listener = NotificationListener::connect(&db.database_url, ALL_CHANNELS)
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+
+    let (client, mut connection) = tokio_postgres::connect(&db.database_url, NoTls)
+        .await
+        .expect("failed to connect");
+    let (payloads, mut received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(Ok(message)) = messages.next().await {
+            if let AsyncMessage::Notification(notification) = message {
+                let _ = payloads.send(notification.payload().to_string());
+            }
+        }
+    });
+    client.batch_execute("LISTEN system_insert").await.unwrap();
+
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+
+    let payload = tokio::time::timeout(RECEIVE_TIMEOUT, received.recv())
+        .await
+        .expect("no payload received")
+        .unwrap();
+
+    let parsed: NotificationPayload =
+        serde_json::from_str(&payload).expect("payload does not deserialize");
+    assert_eq!(parsed.op, Operation::Insert);
+    assert_eq!(parsed.id, id);
+    assert_eq!(parsed.map_id, map_id);
+    let system = parsed.new.expect("row missing from payload");
+
+    let raw: serde_json::Value = serde_json::from_str(&payload).unwrap();
+    let columns: BTreeSet<_> = raw["new"]
+        .as_object()
+        .expect("row is not an object")
+        .keys()
+        .cloned()
+        .collect();
+    let fields: BTreeSet<_> = serde_json::to_value(&system)
+        .unwrap()
+        .as_object()
+        .unwrap()
+        .keys()
+        .filter(|key| *key != "wormhole")
+        .cloned()
+        .collect();
+    assert_eq!(
+        columns, fields,
+        "map_system_v1 columns and MapSystem fields differ"
+    );
+
+    let notification = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("no notification received")
+        .unwrap();
+    assert!(
+        matches!(notification, SystemNotification::Insert(ref system) if system.id == id),
+        "expected the insert, got {:?}",
+        notification
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn shutdown_closes_the_connection_cleanly() {
+    let db = common::start().await;
+    let listener = NotificationListener::connect(&db.database_url, ALL_CHANNELS)
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+
+    assert!(listener.shutdown(Duration::from_secs(5)).await);
+    assert!(!listener.status().connected);
+    // Subscribers see the channel close, so forwarders can drain and stop
+    let closed = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("channel still open");
+    assert!(matches!(closed, Err(RecvError::Closed)));
+    assert!(matches!(
+        listener.subscribe().recv().await,
+        Err(RecvError::Closed)
+    ));
+    // Already stopped
+    assert!(listener.shutdown(Duration::from_secs(5)).await);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn listeners_can_start_together() {
+    let db = common::start().await;
+    let (first, second) = tokio::join!(
+        NotificationListener::connect(&db.database_url, ALL_CHANNELS),
+        NotificationListener::connect(&db.database_url, ALL_CHANNELS),
+    );
+    let first = first.expect("failed to start first listener");
+    let second = second.expect("failed to start second listener");
+    let mut first_events = first.subscribe();
+    let mut second_events = second.subscribe();
+
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+
+    for events in [&mut first_events, &mut second_events] {
+        let event = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+            .await
+            .expect("timed out waiting for notification")
+            .expect("channel closed");
+        assert_eq!(event.system_id(), Some(id));
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn polling_publishes_system_changes() {
+    let db = common::start().await;
+    let listener = NotificationListener::builder(&db.database_url)
+        .channels(ALL_CHANNELS)
+        .mode(NotifyMode::Poll)
+        .poll_interval(Duration::from_millis(100))
+        .connect()
+        .await
+        .expect("failed to start listener");
+    assert!(listener.status().polling);
+    let mut events = listener.subscribe();
+
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    let event = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("timed out waiting for insert")
+        .expect("channel closed");
+    assert!(matches!(event, SystemNotification::Insert(system) if system.id == id));
+
+    // Polling compares timestamps, so make sure the update is strictly newer
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    common::set_description(&db.pool, id, "polled").await;
+    common::touch_system(&db.pool, id).await;
+    let event = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("timed out waiting for update")
+        .expect("channel closed");
+    assert!(matches!(
+        event,
+        SystemNotification::Update { new, .. } if new.description.as_deref() == Some("polled")
+    ));
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn polling_publishes_system_deletes() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    let listener = NotificationListener::builder(&db.database_url)
+        .channels(ALL_CHANNELS)
+        .mode(NotifyMode::Poll)
+        .poll_interval(Duration::from_millis(100))
+        .connect()
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+    // Seen by a poll, so the polls have learnt which systems exist
+    common::insert_system(&db.pool, map_id, 31000002, "J100002").await;
+    tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("timed out waiting for insert")
+        .expect("channel closed");
+
+    MapSystemRepository::delete_system(&db.pool, id, None)
+        .await
+        .unwrap();
+    let event = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("timed out waiting for delete")
+        .expect("channel closed");
+    assert!(matches!(
+        event,
+        SystemNotification::Delete { id: deleted, map_id: on } if deleted == id && on == map_id
+    ));
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn polling_sees_a_delete_hidden_by_an_insert() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    let listener = NotificationListener::builder(&db.database_url)
+        .channels(ALL_CHANNELS)
+        .mode(NotifyMode::Poll)
+        .poll_interval(Duration::from_millis(500))
+        .connect()
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+    common::insert_system(&db.pool, map_id, 31000002, "J100002").await;
+    tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("timed out waiting for insert")
+        .expect("channel closed");
+
+    // Within one interval, so the number of systems never changes between polls
+    MapSystemRepository::delete_system(&db.pool, id, None)
+        .await
+        .unwrap();
+    common::insert_system(&db.pool, map_id, 31000003, "J100003").await;
+
+    let mut deleted = false;
+    for _ in 0..2 {
+        let event = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+            .await
+            .expect("timed out waiting for the poll")
+            .expect("channel closed");
+        deleted |= matches!(event, SystemNotification::Delete { id: gone, .. } if gone == id);
+    }
+    assert!(deleted, "the delete was not published");
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn polling_finds_changes_committed_after_the_mark() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let listener = NotificationListener::builder(&db.database_url)
+        .channels(ALL_CHANNELS)
+        .mode(NotifyMode::Poll)
+        .poll_interval(Duration::from_millis(100))
+        .connect()
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+
+    // Started before the change below, so its `updated_at` ends up behind the mark
+    let (commit, committing) = std::sync::mpsc::channel::<()>();
+    let pool = db.pool.clone();
+    let slow = tokio::task::spawn_blocking(move || {
+        let mut conn = db::get_connection(&pool).unwrap();
+        conn.transaction(|conn| {
+            let id = diesel::insert_into(map_system_v1::table)
+                .values((
+                    map_system_v1::map_id.eq(map_id),
+                    map_system_v1::solar_system_id.eq(31000001),
+                    map_system_v1::name.eq("J100001"),
+                ))
+                .returning(map_system_v1::id)
+                .get_result::<Uuid>(conn)?;
+            committing.recv().unwrap();
+            Ok::<_, diesel::result::Error>(id)
+        })
+        .unwrap()
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let fast = common::insert_system(&db.pool, map_id, 31000002, "J100002").await;
+    let event = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("timed out waiting for the first insert")
+        .expect("channel closed");
+    assert_eq!(event.system_id(), Some(fast));
+
+    commit.send(()).unwrap();
+    let slow = slow.await.unwrap();
+    let event = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("timed out waiting for the late commit")
+        .expect("channel closed");
+    assert_eq!(event.system_id(), Some(slow));
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn moving_a_system_is_a_position_only_update() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+
+    let listener = NotificationListener::connect(&db.database_url, ALL_CHANNELS)
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+
+    MapSystemRepository::set_position(&db.pool, id, 120, -40, None)
+        .await
+        .unwrap()
+        .expect("system not found");
+    let moved = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("no notification received")
+        .unwrap();
+    assert!(moved.is_position_only());
+
+    common::set_description(&db.pool, id, "not a move").await;
+    let edited = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+        .await
+        .expect("no notification received")
+        .unwrap();
+    assert!(!edited.is_position_only());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn injected_notification_reaches_subscribers() {
+    let db = common::start().await;
+    let listener = NotificationListener::connect(&db.database_url, ALL_CHANNELS)
+        .await
+        .expect("failed to start listener");
+    let mut events = listener.subscribe();
+    let map_id = Uuid::new_v4();
+    let mut map_events = listener.subscribe_map(map_id);
+    let mut closed = listener.subscribe_closed();
+
+    // As a client would send it
+    let id = Uuid::new_v4();
+    let notification: SystemNotification = serde_json::from_value(serde_json::json!({
+        "type": "delete",
+        "data": { "id": id, "map_id": map_id },
+    }))
+    .unwrap();
+    assert!(listener.inject(notification));
+
+    for events in [&mut events, &mut map_events] {
+        let event = tokio::time::timeout(RECEIVE_TIMEOUT, events.recv())
+            .await
+            .expect("no notification received")
+            .unwrap();
+        assert_eq!(event.system_id(), Some(id));
+    }
+
+    listener.close_map(map_id);
+    assert_eq!(closed.recv().await.unwrap(), map_id);
+    assert!(matches!(map_events.recv().await, Err(RecvError::Closed)));
+
+    assert!(listener.shutdown(Duration::from_secs(5)).await);
+    let notification = SystemNotification::Delete { id, map_id };
+    assert!(!listener.inject(notification));
+}
@@ -0,0 +1,61 @@
+//! `RateLimiter` buckets. Runs without Docker.
+
+use std::net::SocketAddr;
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{Request, StatusCode};
+use axum::routing::get;
+use axum::{middleware, Router};
+use tower::ServiceExt;
+use wanderer_connector::rate_limit::{self, RateLimiter};
+
+#[test]
+fn retry_after_does_not_take_a_token() {
+    let limiter = RateLimiter::new(0.001, 2);
+
+    assert_eq!(limiter.retry_after("ip:10.0.0.1"), None);
+    assert_eq!(limiter.retry_after("ip:10.0.0.1"), None);
+    assert!(limiter.check("ip:10.0.0.1").is_ok());
+    assert!(limiter.check("ip:10.0.0.1").is_ok());
+
+    assert!(limiter.retry_after("ip:10.0.0.1").is_some());
+    assert!(limiter.check("ip:10.0.0.1").is_err());
+    // Buckets are per key
+    assert!(limiter.check("ip:10.0.0.2").is_ok());
+}
+
+#[tokio::test]
+async fn auth_failures_and_public_routes_have_separate_budgets() {
+    let limiter = RateLimiter::new(0.001, 1);
+    let app = Router::new()
+        .route("/health", get(|| async { StatusCode::OK }))
+        .route_layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            rate_limit::rate_limit,
+        ))
+        .merge(
+            Router::new()
+                .route("/private", get(|| async { StatusCode::UNAUTHORIZED }))
+                .route_layer(middleware::from_fn_with_state(
+                    limiter,
+                    rate_limit::limit_auth_failures,
+                )),
+        );
+    let status = |path: &'static str| {
+        let app = app.clone();
+        async move {
+            let mut request = Request::builder().uri(path).body(Body::empty()).unwrap();
+            let addr = SocketAddr::from(([10, 0, 0, 1], 40000));
+            request.extensions_mut().insert(ConnectInfo(addr));
+            app.oneshot(request).await.unwrap().status()
+        }
+    };
+
+    // The probe spends the IP's only public token, yet the failed authentication is
+    // charged to a bucket of its own
+    assert_eq!(status("/health").await, StatusCode::OK);
+    assert_eq!(status("/private").await, StatusCode::UNAUTHORIZED);
+    assert_eq!(status("/private").await, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(status("/health").await, StatusCode::TOO_MANY_REQUESTS);
+}
@@ -0,0 +1,523 @@
+//! Repository queries against a real Postgres. Needs Docker, so run with
+//! `cargo test -- --ignored`.
+
+mod common;
+
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use uuid::Uuid;
+use wanderer_connector::chain::chain_from_home;
+use wanderer_connector::db::{self, RepoError};
+use wanderer_connector::handlers::{
+    AuditRepository, MapConnectionRepository, MapSignatureRepository, MapSystemRepository,
+};
+use wanderer_connector::models::{Pagination, SyncSummary, SystemSnapshot};
+use wanderer_connector::schema::map_system_signatures_v1;
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn get_system_by_id_returns_inserted_row() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+
+    let system = MapSystemRepository::get_system_by_id(&db.pool, id)
+        .await
+        .unwrap()
+        .expect("system should exist");
+
+    assert_eq!(system.map_id, map_id);
+    assert_eq!(system.solar_system_id, 31000001);
+    assert_eq!(system.name, "J100001");
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn get_system_by_id_returns_none_when_missing() {
+    let db = common::start().await;
+
+    let system = MapSystemRepository::get_system_by_id(&db.pool, Uuid::new_v4())
+        .await
+        .unwrap();
+
+    assert!(system.is_none());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn get_by_solar_system_id_is_scoped_to_the_map() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    common::insert_system(&db.pool, map_id, 30000142, "Jita").await;
+
+    let found = MapSystemRepository::get_by_solar_system_id(&db.pool, map_id, 30000142)
+        .await
+        .unwrap();
+    let other_map = MapSystemRepository::get_by_solar_system_id(&db.pool, Uuid::new_v4(), 30000142)
+        .await
+        .unwrap();
+
+    assert_eq!(found.map(|system| system.name), Some("Jita".to_string()));
+    assert!(other_map.is_none());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn get_systems_by_map_id_pages_by_name() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    for (solar_system_id, name) in [(31000003, "C"), (31000001, "A"), (31000002, "B")] {
+        common::insert_system(&db.pool, map_id, solar_system_id, name).await;
+    }
+    // Systems on other maps are not counted
+    common::insert_system(&db.pool, Uuid::new_v4(), 31000004, "D").await;
+
+    let pagination = Pagination {
+        limit: Some(2),
+        offset: Some(1),
+    };
+    let page = MapSystemRepository::get_systems_by_map_id(&db.pool, map_id, pagination)
+        .await
+        .unwrap();
+
+    let names: Vec<_> = page
+        .items
+        .iter()
+        .map(|system| system.name.as_str())
+        .collect();
+    assert_eq!(names, ["B", "C"]);
+    assert_eq!(page.total, 3);
+    assert_eq!(page.limit, 2);
+    assert_eq!(page.offset, 1);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn delete_by_map_id_removes_only_that_map() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let other_map = Uuid::new_v4();
+    common::insert_system(&db.pool, map_id, 31000001, "A").await;
+    common::insert_system(&db.pool, map_id, 31000002, "B").await;
+    common::insert_system(&db.pool, other_map, 31000001, "A").await;
+
+    let deleted = MapSystemRepository::delete_by_map_id(&db.pool, map_id, None)
+        .await
+        .unwrap();
+
+    assert_eq!(deleted, 2);
+    let remaining = |map_id| {
+        MapSystemRepository::get_systems_by_map_id(&db.pool, map_id, Pagination::default())
+    };
+    assert_eq!(remaining(map_id).await.unwrap().total, 0);
+    assert_eq!(remaining(other_map).await.unwrap().total, 1);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn chain_from_home_follows_connections() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let home = common::insert_system(&db.pool, map_id, 31000001, "Home").await;
+    let static_hole = common::insert_system(&db.pool, map_id, 31000002, "Static").await;
+    let beyond = common::insert_system(&db.pool, map_id, 31000003, "Beyond").await;
+    let unlinked = common::insert_system(&db.pool, map_id, 31000004, "Unlinked").await;
+    common::insert_connection(&db.pool, map_id, home, static_hole).await;
+    common::insert_connection(&db.pool, map_id, beyond, static_hole).await;
+
+    let chain = chain_from_home(&db.pool, map_id, &[home]).await.unwrap();
+
+    assert_eq!(chain[0], home);
+    assert!(chain.contains(&static_hole));
+    assert!(chain.contains(&beyond));
+    assert!(!chain.contains(&unlinked));
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn chain_from_home_joins_several_homes() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let staging = common::insert_system(&db.pool, map_id, 31000001, "Staging").await;
+    let second_staging = common::insert_system(&db.pool, map_id, 31000002, "Second").await;
+    let shared = common::insert_system(&db.pool, map_id, 31000003, "Shared").await;
+    let beyond = common::insert_system(&db.pool, map_id, 31000004, "Beyond").await;
+    let unlinked = common::insert_system(&db.pool, map_id, 31000005, "Unlinked").await;
+    common::insert_connection(&db.pool, map_id, staging, shared).await;
+    common::insert_connection(&db.pool, map_id, second_staging, shared).await;
+    common::insert_connection(&db.pool, map_id, second_staging, beyond).await;
+
+    let chain = chain_from_home(&db.pool, map_id, &[staging, second_staging])
+        .await
+        .unwrap();
+
+    assert_eq!(&chain[..2], &[staging, second_staging]);
+    assert_eq!(chain.len(), 4, "shared system listed once: {:?}", chain);
+    assert!(chain.contains(&shared));
+    assert!(chain.contains(&beyond));
+    assert!(!chain.contains(&unlinked));
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn get_systems_by_label_parses_both_encodings() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let json = common::insert_system(&db.pool, map_id, 31000001, "A").await;
+    let comma = common::insert_system(&db.pool, map_id, 31000002, "B").await;
+    let substring = common::insert_system(&db.pool, map_id, 31000003, "C").await;
+    let empty = common::insert_system(&db.pool, map_id, 31000004, "D").await;
+    common::set_labels(&db.pool, json, r#"["Staging", "hole"]"#).await;
+    common::set_labels(&db.pool, comma, "hole, staging").await;
+    common::set_labels(&db.pool, substring, "prestaging").await;
+    common::set_labels(&db.pool, empty, "").await;
+
+    let page = MapSystemRepository::get_systems_by_label(
+        &db.pool,
+        map_id,
+        "staging".to_string(),
+        Pagination::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(page.total, 2);
+    let ids: Vec<_> = page.items.iter().map(|system| system.id).collect();
+    assert_eq!(ids, [json, comma]);
+    assert_eq!(page.items[0].labels, ["Staging", "hole"]);
+
+    let system = MapSystemRepository::get_system_by_id(&db.pool, empty)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(system.labels.is_empty());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn count_systems_applies_the_label_filter() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let json = common::insert_system(&db.pool, map_id, 31000001, "A").await;
+    let substring = common::insert_system(&db.pool, map_id, 31000002, "B").await;
+    common::insert_system(&db.pool, map_id, 31000003, "C").await;
+    common::set_labels(&db.pool, json, r#"["Staging"]"#).await;
+    common::set_labels(&db.pool, substring, "prestaging").await;
+
+    let count = |label: Option<&str>| {
+        MapSystemRepository::count_systems(&db.pool, map_id, label.map(str::to_string))
+    };
+    assert_eq!(count(None).await.unwrap(), 3);
+    assert_eq!(count(Some("staging")).await.unwrap(), 1);
+    assert_eq!(count(Some("missing")).await.unwrap(), 0);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn delete_system_removes_its_signatures() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    let kept = common::insert_system(&db.pool, map_id, 31000002, "J100002").await;
+    common::insert_signature(&db.pool, id, "ABC-123").await;
+    common::insert_signature(&db.pool, kept, "DEF-456").await;
+    common::insert_connection(&db.pool, map_id, id, kept).await;
+
+    assert!(MapSystemRepository::delete_system(&db.pool, id, None)
+        .await
+        .unwrap());
+    assert!(!MapSystemRepository::delete_system(&db.pool, id, None)
+        .await
+        .unwrap());
+
+    let signatures =
+        |system_id| MapSignatureRepository::get_signatures_by_system_id(&db.pool, system_id);
+    assert!(signatures(id).await.unwrap().is_empty());
+    assert_eq!(signatures(kept).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn with_transaction_rolls_back_on_failure() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    common::insert_signature(&db.pool, id, "ABC-123").await;
+
+    let result = db::with_transaction(&db.pool, move |conn| {
+        diesel::delete(
+            map_system_signatures_v1::table.filter(map_system_signatures_v1::system_id.eq(id)),
+        )
+        .execute(conn)?;
+        // Fails after the first statement went through
+        Err::<(), _>(DieselError::RollbackTransaction)
+    })
+    .await;
+
+    assert!(result.is_err());
+    let signatures = MapSignatureRepository::get_signatures_by_system_id(&db.pool, id)
+        .await
+        .unwrap();
+    assert_eq!(signatures.len(), 1, "delete was not rolled back");
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn panicking_query_is_a_join_error() {
+    let db = common::start().await;
+
+    let result = db::run(&db.pool, |_conn| -> QueryResult<()> {
+        panic!("query blew up")
+    })
+    .await;
+
+    match result {
+        Err(RepoError::Join { task, source }) => {
+            assert!(source.is_panic());
+            assert!(
+                task.ends_with("panicking_query_is_a_join_error"),
+                "unexpected task name {}",
+                task
+            );
+        }
+        other => panic!("expected a join error, got {:?}", other),
+    }
+    // The pool survives the panic
+    db::ping(&db.pool).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn panicking_transaction_is_named_after_its_caller() {
+    let db = common::start().await;
+
+    let result = db::with_transaction(&db.pool, |_conn| -> QueryResult<()> {
+        panic!("transaction blew up")
+    })
+    .await;
+
+    match result {
+        Err(RepoError::Join { task, .. }) => assert!(
+            task.ends_with("panicking_transaction_is_named_after_its_caller"),
+            "unexpected task name {}",
+            task
+        ),
+        other => panic!("expected a join error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn get_neighbors_returns_one_hop() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let home = common::insert_system(&db.pool, map_id, 31000001, "Home").await;
+    let static_hole = common::insert_system(&db.pool, map_id, 31000002, "Static").await;
+    let beyond = common::insert_system(&db.pool, map_id, 31000003, "Beyond").await;
+    let isolated = common::insert_system(&db.pool, map_id, 31000004, "Isolated").await;
+    common::insert_connection(&db.pool, map_id, home, static_hole).await;
+    common::insert_connection(&db.pool, map_id, beyond, static_hole).await;
+
+    let neighbors = MapConnectionRepository::get_neighbors(&db.pool, static_hole)
+        .await
+        .unwrap();
+
+    let ids: Vec<_> = neighbors
+        .iter()
+        .map(|neighbor| neighbor.system.id)
+        .collect();
+    assert_eq!(ids, [beyond, home]);
+    assert!(neighbors.iter().all(|neighbor| {
+        [
+            neighbor.connection.source_system_id,
+            neighbor.connection.target_system_id,
+        ]
+        .contains(&static_hole)
+    }));
+    assert!(MapConnectionRepository::get_neighbors(&db.pool, isolated)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn transaction_may_outlast_the_statement_timeout() {
+    let db = common::start().await;
+
+    // Each statement stays under the default 5s statement timeout, the two together don't
+    let slept = db::with_transaction(&db.pool, |conn| {
+        diesel::sql_query("SELECT pg_sleep(3)").execute(conn)?;
+        diesel::sql_query("SELECT pg_sleep(3)").execute(conn)
+    })
+    .await;
+
+    assert!(slept.is_ok(), "transaction failed: {:?}", slept);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn map_version_changes_with_its_systems() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+
+    let version = || MapSystemRepository::get_map_version(&db.pool, map_id);
+    assert_eq!(version().await.unwrap(), (0, None));
+
+    common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    let first = version().await.unwrap();
+    assert_eq!(first.0, 1);
+    assert!(first.1.is_some());
+
+    let id = common::insert_system(&db.pool, map_id, 31000002, "J100002").await;
+    assert_eq!(version().await.unwrap().0, 2);
+
+    MapSystemRepository::delete_system(&db.pool, id, None)
+        .await
+        .unwrap();
+    assert_eq!(version().await.unwrap().0, 1);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn deletes_are_audited_with_their_actor() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    let other = common::insert_system(&db.pool, map_id, 31000002, "J100002").await;
+    common::insert_signature(&db.pool, id, "ABC-123").await;
+    common::insert_connection(&db.pool, map_id, id, other).await;
+
+    MapSystemRepository::delete_system(&db.pool, id, Some("api-key:0123456789abcdef".to_string()))
+        .await
+        .unwrap();
+
+    let entries = AuditRepository::get_entries(&db.pool, None, None, Pagination::default())
+        .await
+        .unwrap();
+    let mut tables: Vec<_> = entries
+        .items
+        .iter()
+        .map(|entry| entry.table_name.as_str())
+        .collect();
+    tables.sort();
+    assert_eq!(
+        tables,
+        [
+            "map_connection_v1",
+            "map_system_signatures_v1",
+            "map_system_v1"
+        ]
+    );
+    assert!(entries.items.iter().all(|entry| entry.operation == "delete"
+        && entry.actor.as_deref() == Some("api-key:0123456789abcdef")));
+
+    let systems = AuditRepository::get_entries(
+        &db.pool,
+        Some("map_system_v1".to_string()),
+        None,
+        Pagination::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(systems.total, 1);
+    assert_eq!(systems.items[0].row_id, id.to_string());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn moves_are_audited_with_their_actor() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let id = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+
+    let moved = MapSystemRepository::set_position(
+        &db.pool,
+        id,
+        120,
+        -40,
+        Some("api-key:0123456789abcdef".to_string()),
+    )
+    .await
+    .unwrap()
+    .expect("system not found");
+    assert_eq!((moved.position_x, moved.position_y), (120, -40));
+
+    let entries = AuditRepository::get_entries(&db.pool, None, None, Pagination::default())
+        .await
+        .unwrap();
+    assert_eq!(entries.total, 1);
+    let entry = &entries.items[0];
+    assert_eq!(entry.table_name, "map_system_v1");
+    assert_eq!(entry.row_id, id.to_string());
+    assert_eq!(entry.operation, "update");
+    assert_eq!(entry.actor.as_deref(), Some("api-key:0123456789abcdef"));
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn sync_reconciles_a_map_to_the_snapshot() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let kept = common::insert_system(&db.pool, map_id, 31000001, "J100001").await;
+    let renamed = common::insert_system(&db.pool, map_id, 31000002, "J100002").await;
+    let dropped = common::insert_system(&db.pool, map_id, 31000003, "J100003").await;
+    common::insert_connection(&db.pool, map_id, kept, dropped).await;
+
+    let snapshot: Vec<SystemSnapshot> = serde_json::from_value(serde_json::json!([
+        { "solar_system_id": 31000001, "name": "J100001" },
+        { "solar_system_id": 31000002, "name": "Home", "labels": ["staging"] },
+        { "solar_system_id": 31000004, "name": "J100004" },
+    ]))
+    .unwrap();
+    let summary = MapSystemRepository::sync_map(&db.pool, map_id, snapshot, None)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        summary,
+        SyncSummary {
+            inserted: 1,
+            updated: 1,
+            deleted: 1
+        }
+    );
+    let renamed = MapSystemRepository::get_system_by_id(&db.pool, renamed)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(renamed.name, "Home");
+    assert_eq!(renamed.labels, ["staging"]);
+    assert!(renamed.wormhole);
+    assert!(MapSystemRepository::get_system_by_id(&db.pool, dropped)
+        .await
+        .unwrap()
+        .is_none());
+    let connections = MapConnectionRepository::get_connections_by_map_id(&db.pool, map_id)
+        .await
+        .unwrap();
+    assert!(connections.is_empty());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn concurrent_syncs_of_a_new_map_run_one_after_the_other() {
+    let db = common::start().await;
+    let map_id = Uuid::new_v4();
+    let snapshot: Vec<SystemSnapshot> = serde_json::from_value(serde_json::json!([
+        { "solar_system_id": 31000001, "name": "J100001" },
+    ]))
+    .unwrap();
+
+    let (first, second) = tokio::join!(
+        MapSystemRepository::sync_map(&db.pool, map_id, snapshot.clone(), None),
+        MapSystemRepository::sync_map(&db.pool, map_id, snapshot, None),
+    );
+
+    // The second sync saw the first one's insert instead of repeating it
+    assert_eq!(first.unwrap().inserted + second.unwrap().inserted, 1);
+    let systems = MapSystemRepository::count_systems(&db.pool, map_id, None)
+        .await
+        .unwrap();
+    assert_eq!(systems, 1);
+}
@@ -0,0 +1,16 @@
+//! Change type filters of the event streams. Runs without Docker.
+
+use wanderer_connector::notify::KindFilter;
+
+#[test]
+fn map_streams_refuse_signature_types() {
+    let parse = |types: &str| types.parse::<KindFilter>().unwrap();
+
+    assert!(parse("insert,connection_delete").for_map_stream().is_ok());
+    assert!(parse("").for_map_stream().is_ok());
+
+    let error = parse("insert,signature_update")
+        .for_map_stream()
+        .unwrap_err();
+    assert!(error.contains("signature_update"), "{}", error);
+}
@@ -0,0 +1,27 @@
+//! TLS parameters of the libpq connection URL. Runs without Docker.
+
+use std::path::PathBuf;
+
+use wanderer_connector::db::tls::DbTls;
+
+#[test]
+fn root_cert_path_is_percent_encoded() {
+    let dir = std::env::temp_dir().join(format!("certs & keys #{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let root_cert: PathBuf = dir.join("ca bundle.pem");
+    std::fs::copy("tests/fixtures/ca.pem", &root_cert).unwrap();
+
+    std::env::set_var("DATABASE_SSLMODE", "require");
+    std::env::set_var("DATABASE_SSLROOTCERT", &root_cert);
+    let tls = DbTls::from_env().unwrap().expect("TLS should be enabled");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let url = tls.libpq_url("postgres://wanderer@db/wanderer?application_name=connector");
+    let expected = format!(
+        "postgres://wanderer@db/wanderer?application_name=connector&sslmode=require\
+         &sslrootcert={}/certs%20%26%20keys%20%23{}/ca%20bundle.pem",
+        std::env::temp_dir().display(),
+        std::process::id()
+    );
+    assert_eq!(url, expected);
+}